@@ -1,22 +1,235 @@
-use std::fs;
-use std::iter::Peekable;
+use core::fmt;
+use core::iter::Peekable;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(feature = "std")]
+use std::{env, fs, io};
+
+// Without the `std` feature the decoder only needs an allocator, files, stdio and `main` are
+// left out (see no-std/). Standalone builds enable it with `rustc --cfg 'feature="std"'`
+#[cfg(not(feature = "std"))]
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 type Bit = u8;
 
-#[derive(Debug)]
+// Not every serde format (nor JSON consumers) can handle 128-bit integers
+#[cfg(feature = "serde")]
+mod u128_string {
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+// How an operator announces the size of its sub-packets
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+enum LengthType {
+    TotalBits,
+    SubPacketCount,
+}
+
+impl LengthType {
+    fn field_width(&self) -> usize {
+        match self {
+            LengthType::TotalBits => 15,
+            LengthType::SubPacketCount => 11,
+        }
+    }
+}
+
+// Serialized as `{"kind": "literal" | "operator", ...}`, literal values as decimal strings
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
 enum Packet {
     Literal {
         version: u8,
         type_id: u8,
+        #[cfg_attr(feature = "serde", serde(with = "u128_string"))]
         value: u128,
     },
     Operator {
         version: u8,
         type_id: u8,
+        length_type: LengthType,
         packets: Vec<Packet>,
     },
 }
 
+// Evaluation rules for one operator type id
+#[derive(Clone, Copy)]
+struct OperatorDef {
+    name: &'static str,
+    arity: Arity,
+    // Only called with as many operands as `arity` accepts, `None` on overflow
+    eval: fn(&[u128]) -> Option<u128>,
+    // Nested applications equal a single one over all operands, and a single operand (where
+    // `arity` allows one) is its own result
+    associative: bool,
+    // Operand that never changes the result
+    identity: Option<u128>,
+    // Operand that decides the result on its own
+    absorbing: Option<u128>,
+}
+
+// Type ids are 3 bits wide and 4 always means literal, so there are at most seven operators
+#[derive(Clone)]
+struct OperatorTable {
+    operators: [Option<OperatorDef>; 8],
+}
+
+const PUZZLE_OPERATORS: OperatorTable = OperatorTable {
+    operators: [
+        Some(OperatorDef {
+            name: "sum",
+            arity: Arity::AtLeast(1),
+            eval: eval_sum,
+            associative: true,
+            identity: Some(0),
+            absorbing: None,
+        }),
+        Some(OperatorDef {
+            name: "product",
+            arity: Arity::AtLeast(1),
+            eval: eval_product,
+            associative: true,
+            identity: Some(1),
+            absorbing: Some(0),
+        }),
+        Some(OperatorDef {
+            name: "minimum",
+            arity: Arity::AtLeast(1),
+            eval: eval_minimum,
+            associative: true,
+            identity: None,
+            absorbing: Some(0),
+        }),
+        Some(OperatorDef {
+            name: "maximum",
+            arity: Arity::AtLeast(1),
+            eval: eval_maximum,
+            associative: true,
+            identity: Some(0),
+            absorbing: None,
+        }),
+        None,
+        Some(OperatorDef {
+            name: "greater_than",
+            arity: Arity::Exactly(2),
+            eval: eval_greater_than,
+            associative: false,
+            identity: None,
+            absorbing: None,
+        }),
+        Some(OperatorDef {
+            name: "less_than",
+            arity: Arity::Exactly(2),
+            eval: eval_less_than,
+            associative: false,
+            identity: None,
+            absorbing: None,
+        }),
+        Some(OperatorDef {
+            name: "equal_to",
+            arity: Arity::Exactly(2),
+            eval: eval_equal_to,
+            associative: false,
+            identity: None,
+            absorbing: None,
+        }),
+    ],
+};
+
+impl OperatorTable {
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn empty() -> OperatorTable {
+        OperatorTable {
+            operators: [None; 8],
+        }
+    }
+
+    // Adds or replaces an operator, e.g. `PUZZLE_OPERATORS.with(5, xor)`
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn with(mut self, type_id: u8, operator: OperatorDef) -> OperatorTable {
+        assert!(
+            type_id < 8 && type_id != 4,
+            "type id {} cannot be an operator",
+            type_id
+        );
+        self.operators[type_id as usize] = Some(operator);
+        self
+    }
+
+    fn get(&self, type_id: u8) -> Option<&OperatorDef> {
+        self.operators.get(type_id as usize)?.as_ref()
+    }
+
+    fn find(&self, name: &str) -> Option<u8> {
+        (0..8).find(|i| self.get(*i).map(|op| op.name) == Some(name))
+    }
+}
+
+impl Default for OperatorTable {
+    fn default() -> Self {
+        PUZZLE_OPERATORS
+    }
+}
+
+fn eval_sum(values: &[u128]) -> Option<u128> {
+    values.iter().try_fold(0u128, |a, b| a.checked_add(*b))
+}
+
+fn eval_product(values: &[u128]) -> Option<u128> {
+    // Zero wins regardless of order, even if the other factors alone would overflow
+    if values.contains(&0) {
+        return Some(0);
+    }
+    values.iter().try_fold(1u128, |a, b| a.checked_mul(*b))
+}
+
+fn eval_minimum(values: &[u128]) -> Option<u128> {
+    values.iter().copied().min()
+}
+
+fn eval_maximum(values: &[u128]) -> Option<u128> {
+    values.iter().copied().max()
+}
+
+fn eval_greater_than(values: &[u128]) -> Option<u128> {
+    Some((values[0] > values[1]) as u128)
+}
+
+fn eval_less_than(values: &[u128]) -> Option<u128> {
+    Some((values[0] < values[1]) as u128)
+}
+
+fn eval_equal_to(values: &[u128]) -> Option<u128> {
+    Some((values[0] == values[1]) as u128)
+}
+
 impl Packet {
     fn version(&self) -> u8 {
         match self {
@@ -32,158 +245,2465 @@ impl Packet {
             Packet::Operator {
                 packets: sub_packets,
                 ..
-            } => std::iter::once(self)
+            } => core::iter::once(self)
                 .chain(sub_packets.iter().flat_map(|p| p.flatten()))
                 .collect(),
         }
     }
 
+    fn type_id(&self) -> u8 {
+        match self {
+            Packet::Literal { type_id, .. } => *type_id,
+            Packet::Operator { type_id, .. } => *type_id,
+        }
+    }
+
+    fn version_sum(&self) -> u128 {
+        self.flatten().iter().map(|a| a.version() as u128).sum()
+    }
+
     // Task 2
     fn eval(&self) -> u128 {
+        self.checked_eval()
+            .expect("packet is invalid or its value overflows, see `validate`")
+    }
+
+    // `None` instead of a panic for packets that fail validation or overflow u128
+    fn checked_eval(&self) -> Option<u128> {
+        self.checked_eval_with(&PUZZLE_OPERATORS)
+    }
+
+    fn checked_eval_with(&self, operators: &OperatorTable) -> Option<u128> {
         match self {
-            Packet::Literal { value, .. } => *value,
+            Packet::Literal { value, .. } => Some(*value),
             Packet::Operator {
                 packets, type_id, ..
-            } => match *type_id {
-                0 => packets.iter().map(|p| p.eval()).sum(),
-                1 => packets.iter().map(|p| p.eval()).product(),
-                2 => packets.iter().map(|p| p.eval()).min().unwrap(),
-                3 => packets.iter().map(|p| p.eval()).max().unwrap(),
-                5 => (packets[0].eval() > packets[1].eval()) as u128,
-                6 => (packets[0].eval() < packets[1].eval()) as u128,
-                7 => (packets[0].eval() == packets[1].eval()) as u128,
-                _ => unreachable!(),
-            },
+            } => {
+                let operator = operators.get(*type_id)?;
+                if !operator.arity.accepts(packets.len()) {
+                    return None;
+                }
+
+                let values = packets
+                    .iter()
+                    .map(|p| p.checked_eval_with(operators))
+                    .collect::<Option<Vec<_>>>()?;
+
+                (operator.eval)(&values)
+            }
         }
     }
-}
 
-type BitIterator = dyn Iterator<Item = Bit>;
+    // Inverse of `BitStream::read_packet`, literals use as few groups as possible
+    fn encode(&self, bits: &mut Vec<Bit>) {
+        push_bits(bits, self.version() as u128, 3);
 
-struct BitStream {
-    iter: Peekable<Box<BitIterator>>,
-}
+        match self {
+            Packet::Literal { type_id, value, .. } => {
+                push_bits(bits, *type_id as u128, 3);
 
-impl BitStream {
-    fn from_iter<I: 'static + Iterator<Item = Bit>>(iter: I) -> BitStream {
-        let iter: Box<BitIterator> = Box::new(iter);
-        BitStream {
-            iter: iter.peekable(),
+                for i in (0..literal_groups(*value)).rev() {
+                    push_bits(bits, (i > 0) as u128, 1);
+                    push_bits(bits, (value >> (i * 4)) & 0xF, 4);
+                }
+            }
+            Packet::Operator {
+                type_id,
+                length_type,
+                packets,
+                ..
+            } => {
+                push_bits(bits, *type_id as u128, 3);
+
+                let mut sub_bits = vec![];
+                for packet in packets {
+                    packet.encode(&mut sub_bits);
+                }
+
+                let length = match length_type {
+                    LengthType::TotalBits => sub_bits.len(),
+                    LengthType::SubPacketCount => packets.len(),
+                };
+                let width = length_type.field_width();
+                assert!(length < 1 << width, "length field overflow, see `validate`");
+
+                push_bits(
+                    bits,
+                    (*length_type == LengthType::SubPacketCount) as u128,
+                    1,
+                );
+                push_bits(bits, length as u128, width);
+                bits.extend(sub_bits);
+            }
         }
     }
 
-    fn from_vec(data: Vec<Bit>) -> BitStream {
-        BitStream::from_iter(data.into_iter())
+    // Length of `encode`'s output, without building it
+    fn bit_len(&self) -> usize {
+        6 + match self {
+            Packet::Literal { value, .. } => 5 * literal_groups(*value),
+            Packet::Operator {
+                length_type,
+                packets,
+                ..
+            } => 1 + length_type.field_width() + packets.iter().map(|p| p.bit_len()).sum::<usize>(),
+        }
     }
 
-    fn from_hex_str(data: &str) -> BitStream {
-        BitStream::from_vec(
-            data.trim()
-                .chars()
-                .flat_map(|c| {
-                    let val = Bit::from_str_radix(&c.to_string(), 16).unwrap();
-                    let bits = (0..4).rev().map(move |i| (val >> i) & 1);
-                    bits
-                })
-                .collect(),
-        )
+    fn to_bits(&self) -> Vec<Bit> {
+        let mut bits = vec![];
+        self.encode(&mut bits);
+        bits
     }
 
-    fn from_binary_str(data: &str) -> BitStream {
-        BitStream::from_vec(
-            data.trim()
-                .chars()
-                .map(|c| Bit::from_str_radix(&c.to_string(), 2).unwrap())
-                .collect(),
-        )
+    fn to_hex(&self) -> String {
+        bits_to_hex(&self.to_bits())
     }
+}
 
-    fn read_bits(&mut self, n: usize) -> u128 {
-        self.iter
-            .by_ref()
-            .take(n)
-            .fold(0, |a, b| (a << 1) + b as u128)
+// Simplification
+impl Packet {
+    fn literal_value(&self) -> Option<u128> {
+        match self {
+            Packet::Literal { value, .. } => Some(*value),
+            Packet::Operator { .. } => None,
+        }
     }
 
-    fn read_bit(&mut self) -> bool {
-        self.read_bits(1) > 0
+    // Same `eval`, fewer packets; versions of packets that get folded away are lost
+    fn simplify(&self) -> Packet {
+        self.simplify_with(&PUZZLE_OPERATORS)
     }
 
-    fn read_packet(&mut self) -> Packet {
-        let version = self.read_bits(3) as u8;
-        let type_id = self.read_bits(3) as u8;
+    fn simplify_with(&self, operators: &OperatorTable) -> Packet {
+        let (version, type_id, length_type, packets) = match self {
+            Packet::Literal { .. } => return self.clone(),
+            Packet::Operator {
+                version,
+                type_id,
+                length_type,
+                packets,
+            } => (*version, *type_id, *length_type, packets),
+        };
+        let literal = |value| Packet::Literal {
+            version,
+            type_id: 4,
+            value,
+        };
 
-        let result = match type_id {
-            4 => {
-                let value = std::iter::successors(Some(self.read_bits(5)), |a| {
-                    if a >> 4 & 1 != 0 {
-                        Some(self.read_bits(5))
-                    } else {
-                        None
-                    }
-                })
-                .fold(0, |a, b| (a << 4) + (b & (0xF)));
+        let operator = match operators.get(type_id) {
+            Some(operator) => operator,
+            None => return self.clone(),
+        };
 
-                Packet::Literal {
-                    version,
-                    type_id,
-                    value,
+        // BITS has no variables, so every packet with a value is a constant
+        if let Some(value) = self.checked_eval_with(operators) {
+            return literal(value);
+        }
+
+        // What is left is invalid or overflows; dropping operands that never change the result
+        // keeps it that way
+        let mut packets: Vec<Packet> = packets.iter().map(|p| p.simplify_with(operators)).collect();
+        // A wrong operand count is the problem itself, so only the operands are touched
+        if !operator.associative || !operator.arity.accepts(packets.len()) {
+            return Packet::Operator {
+                version,
+                type_id,
+                length_type,
+                packets,
+            };
+        }
+
+        let is_literal = |p: &Packet, value| p.literal_value() == Some(value);
+        if let Some(identity) = operator.identity {
+            let remaining = packets.iter().filter(|p| !is_literal(p, identity)).count();
+            if operator.arity.accepts(remaining) {
+                packets.retain(|p| !is_literal(p, identity));
+            }
+        }
+
+        if packets.len() == 1 {
+            return packets.pop().unwrap();
+        }
+
+        Packet::Operator {
+            version,
+            type_id,
+            length_type,
+            packets,
+        }
+    }
+
+    // Picks, per operator, the length field that makes the bit stream shortest
+    fn with_shortest_lengths(&self) -> Packet {
+        match self {
+            Packet::Literal { .. } => self.clone(),
+            Packet::Operator {
+                version,
+                type_id,
+                length_type,
+                packets,
+            } => {
+                let packets: Vec<Packet> =
+                    packets.iter().map(|p| p.with_shortest_lengths()).collect();
+                let total_bits: usize = packets.iter().map(|p| p.bit_len()).sum();
+
+                let length_type = [LengthType::SubPacketCount, LengthType::TotalBits]
+                    .iter()
+                    .copied()
+                    .filter(|t| {
+                        let length = match t {
+                            LengthType::TotalBits => total_bits,
+                            LengthType::SubPacketCount => packets.len(),
+                        };
+                        length < 1 << t.field_width()
+                    })
+                    .min_by_key(|t| t.field_width())
+                    .unwrap_or(*length_type);
+
+                Packet::Operator {
+                    version: *version,
+                    type_id: *type_id,
+                    length_type,
+                    packets,
                 }
             }
-            _ => {
-                let sub_packets: Vec<Packet> = match self.read_bit() {
-                    true => {
-                        let sub_count = self.read_bits(11);
-                        (0..sub_count).map(|_| self.read_packet()).collect()
-                    }
-                    false => {
-                        let sub_bits = self.read_bits(15);
-                        let sub_iter = self
-                            .iter
-                            .by_ref()
-                            .take(sub_bits as usize)
-                            .collect::<Vec<_>>()
-                            .into_iter();
+        }
+    }
+}
 
-                        let mut bit_stream = BitStream::from_iter(sub_iter);
+// Expression form, e.g. `sum(1, product(2, 3))`
+struct Expression<'a> {
+    packet: &'a Packet,
+    operators: &'a OperatorTable,
+}
 
-                        let mut result = vec![];
-                        while bit_stream.iter.peek().is_some() {
-                            result.push(bit_stream.read_packet());
-                        }
+impl Packet {
+    fn display_with<'a>(&'a self, operators: &'a OperatorTable) -> Expression<'a> {
+        Expression {
+            packet: self,
+            operators,
+        }
+    }
+}
 
-                        result
+impl fmt::Display for Expression<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.packet {
+            Packet::Literal { value, .. } => write!(f, "{}", value),
+            Packet::Operator {
+                type_id, packets, ..
+            } => {
+                match self.operators.get(*type_id) {
+                    Some(operator) => write!(f, "{}(", operator.name)?,
+                    None => write!(f, "op{}(", type_id)?,
+                }
+                for (i, packet) in packets.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
                     }
+                    write!(f, "{}", packet.display_with(self.operators))?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display_with(&PUZZLE_OPERATORS))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    fn accepts(&self, n: usize) -> bool {
+        match *self {
+            Arity::Exactly(k) => n == k,
+            Arity::AtLeast(k) => n >= k,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exactly(k) => write!(f, "exactly {}", k),
+            Arity::AtLeast(k) => write!(f, "at least {}", k),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Violation {
+    VersionOutOfRange {
+        version: u8,
+    },
+    ReservedTypeId {
+        type_id: u8,
+    },
+    WrongArity {
+        type_id: u8,
+        expected: Arity,
+        found: usize,
+    },
+    NonZeroPadding,
+    UnusedTrailingBits {
+        count: usize,
+    },
+    LengthFieldOverflow {
+        length_type: LengthType,
+        length: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::VersionOutOfRange { version } => {
+                write!(f, "version {} does not fit in 3 bits", version)
+            }
+            Violation::ReservedTypeId { type_id } => {
+                write!(f, "type id {} is reserved for this packet kind", type_id)
+            }
+            Violation::WrongArity {
+                type_id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "operator {} expects {} sub-packets, found {}",
+                type_id, expected, found
+            ),
+            Violation::NonZeroPadding => write!(f, "padding contains non-zero bits"),
+            Violation::UnusedTrailingBits { count } => {
+                write!(f, "{} unused bits after the outermost packet", count)
+            }
+            Violation::LengthFieldOverflow {
+                length_type,
+                length,
+            } => write!(
+                f,
+                "length {} does not fit in the {}-bit {:?} field",
+                length,
+                length_type.field_width(),
+                length_type
+            ),
+        }
+    }
+}
+
+// A rule violation and the path of child indices leading to the offending packet
+#[derive(Debug, Clone, PartialEq)]
+struct Issue {
+    path: Vec<usize>,
+    violation: Violation,
+}
+
+fn format_path(path: &[usize]) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+
+    path.iter().map(|i| format!("/{}", i)).collect()
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", format_path(&self.path), self.violation)
+    }
+}
+
+fn literal_groups(value: u128) -> usize {
+    (128 - value.leading_zeros() as usize).div_ceil(4).max(1)
+}
+
+fn push_bits(bits: &mut Vec<Bit>, value: u128, width: usize) {
+    bits.extend((0..width).rev().map(|i| ((value >> i) & 1) as Bit));
+}
+
+// Zero-padded to a whole number of hex digits
+fn bits_to_hex(bits: &[Bit]) -> String {
+    bits.chunks(4)
+        .map(|chunk| {
+            let digit = (0..4).fold(0, |a, i| (a << 1) + *chunk.get(i).unwrap_or(&0) as u32);
+            core::char::from_digit(digit, 16)
+                .unwrap()
+                .to_ascii_uppercase()
+        })
+        .collect()
+}
+
+impl Packet {
+    // Every rule `eval` relies on, checked without panicking
+    fn validate(&self) -> Vec<Issue> {
+        self.validate_with(&PUZZLE_OPERATORS)
+    }
+
+    fn validate_with(&self, operators: &OperatorTable) -> Vec<Issue> {
+        let mut issues = vec![];
+        self.validate_at(operators, &mut vec![], &mut issues);
+        issues
+    }
+
+    fn validate_at(
+        &self,
+        operators: &OperatorTable,
+        path: &mut Vec<usize>,
+        issues: &mut Vec<Issue>,
+    ) {
+        let mut report = |violation| {
+            issues.push(Issue {
+                path: path.clone(),
+                violation,
+            })
+        };
+
+        if self.version() > 7 {
+            report(Violation::VersionOutOfRange {
+                version: self.version(),
+            });
+        }
+
+        match self {
+            Packet::Literal { type_id, .. } => {
+                if *type_id != 4 {
+                    report(Violation::ReservedTypeId { type_id: *type_id });
+                }
+            }
+            Packet::Operator {
+                type_id,
+                length_type,
+                packets,
+                ..
+            } => {
+                let length = match length_type {
+                    LengthType::TotalBits => packets.iter().map(|p| p.bit_len()).sum(),
+                    LengthType::SubPacketCount => packets.len(),
                 };
+                if length >= 1 << length_type.field_width() {
+                    report(Violation::LengthFieldOverflow {
+                        length_type: *length_type,
+                        length,
+                    });
+                }
 
-                Packet::Operator {
-                    version,
-                    type_id,
-                    packets: sub_packets,
+                match operators.get(*type_id).map(|op| op.arity) {
+                    Some(expected) if !expected.accepts(packets.len()) => {
+                        report(Violation::WrongArity {
+                            type_id: *type_id,
+                            expected,
+                            found: packets.len(),
+                        })
+                    }
+                    Some(_) => {}
+                    None => report(Violation::ReservedTypeId { type_id: *type_id }),
+                }
+
+                for (i, packet) in packets.iter().enumerate() {
+                    path.push(i);
+                    packet.validate_at(operators, path, issues);
+                    path.pop();
                 }
             }
-        };
+        }
+    }
+}
 
-        result
+// Bits left over after the outermost packet may only pad out the last hex digit
+fn validate_padding(trailing: &[Bit]) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    if trailing.iter().any(|b| *b != 0) {
+        issues.push(Issue {
+            path: vec![],
+            violation: Violation::NonZeroPadding,
+        });
+    }
+
+    if trailing.len() > 3 {
+        issues.push(Issue {
+            path: vec![],
+            violation: Violation::UnusedTrailingBits {
+                count: trailing.len(),
+            },
+        });
     }
+
+    issues
 }
 
-fn main() {
-    let data = fs::read_to_string("input-day-16.txt").unwrap();
+// One difference between two packet trees. Paths are in the new tree, except for removed
+// packets whose last index counts the old tree's sub-packets, like `-` lines in a unified diff
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    Version {
+        path: Vec<usize>,
+        old: u8,
+        new: u8,
+    },
+    Literal {
+        path: Vec<usize>,
+        old: u128,
+        new: u128,
+    },
+    Operator {
+        path: Vec<usize>,
+        old: u8,
+        new: u8,
+    },
+    LengthType {
+        path: Vec<usize>,
+        old: LengthType,
+        new: LengthType,
+    },
+    Replaced {
+        path: Vec<usize>,
+        old: Packet,
+        new: Packet,
+    },
+    Inserted {
+        path: Vec<usize>,
+        packet: Packet,
+    },
+    Removed {
+        path: Vec<usize>,
+        packet: Packet,
+    },
+}
 
-    // let data = "110100101111111000101000";
-    // let data = "00111000000000000110111101000101001010010001001000000000";
-    // let data = "11101110000000001101010000001100100000100011000001100000";
+fn operator_label(type_id: u8, operators: &OperatorTable) -> String {
+    match operators.get(type_id) {
+        Some(operator) => operator.name.to_string(),
+        None => format!("op{}", type_id),
+    }
+}
 
-    let mut stream = BitStream::from_hex_str(&data);
+// Text form of a change, operators and packets named after `operators`
+struct ChangeText<'a> {
+    change: &'a Change,
+    operators: &'a OperatorTable,
+}
 
-    let packet = stream.read_packet();
+impl Change {
+    fn display_with<'a>(&'a self, operators: &'a OperatorTable) -> ChangeText<'a> {
+        ChangeText {
+            change: self,
+            operators,
+        }
+    }
+}
 
-    println!("{:?}", packet);
-    println!("{:?}", stream.iter.by_ref().map(|a| a).collect::<Vec<_>>());
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display_with(&PUZZLE_OPERATORS))
+    }
+}
 
-    let version_sum: u128 = packet.flatten().iter().map(|a| a.version() as u128).sum();
+impl fmt::Display for ChangeText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operators = self.operators;
+        match self.change {
+            Change::Version { path, old, new } => {
+                write!(f, "~ {} version {} -> {}", format_path(path), old, new)
+            }
+            Change::Literal { path, old, new } => {
+                write!(f, "~ {} literal {} -> {}", format_path(path), old, new)
+            }
+            Change::Operator { path, old, new } => write!(
+                f,
+                "~ {} operator {} -> {}",
+                format_path(path),
+                operator_label(*old, operators),
+                operator_label(*new, operators)
+            ),
+            Change::LengthType { path, old, new } => {
+                write!(
+                    f,
+                    "~ {} length type {:?} -> {:?}",
+                    format_path(path),
+                    old,
+                    new
+                )
+            }
+            Change::Replaced { path, old, new } => write!(
+                f,
+                "! {} {} -> {}",
+                format_path(path),
+                old.display_with(operators),
+                new.display_with(operators)
+            ),
+            Change::Inserted { path, packet } => write!(
+                f,
+                "+ {} {}",
+                format_path(path),
+                packet.display_with(operators)
+            ),
+            Change::Removed { path, packet } => write!(
+                f,
+                "- {} {}",
+                format_path(path),
+                packet.display_with(operators)
+            ),
+        }
+    }
+}
 
-    println!("Task 1: {}", version_sum);
-    println!("Task 2: {}", packet.eval());
+impl Change {
+    fn to_json_with(&self, operators: &OperatorTable) -> String {
+        let packet = |p: &Packet| json_string(&p.display_with(operators).to_string());
+        let (change, path, old, new) = match self {
+            Change::Version { path, old, new } => {
+                ("version", path, old.to_string(), new.to_string())
+            }
+            // Quoted like the serde form, not every JSON consumer handles 128-bit integers
+            Change::Literal { path, old, new } => (
+                "literal",
+                path,
+                json_string(&old.to_string()),
+                json_string(&new.to_string()),
+            ),
+            Change::Operator { path, old, new } => (
+                "operator",
+                path,
+                json_string(&operator_label(*old, operators)),
+                json_string(&operator_label(*new, operators)),
+            ),
+            Change::LengthType { path, old, new } => (
+                "length_type",
+                path,
+                json_string(&format!("{:?}", old)),
+                json_string(&format!("{:?}", new)),
+            ),
+            Change::Replaced { path, old, new } => ("replaced", path, packet(old), packet(new)),
+            Change::Inserted { path, packet: p } => {
+                ("inserted", path, "null".to_string(), packet(p))
+            }
+            Change::Removed { path, packet: p } => ("removed", path, packet(p), "null".to_string()),
+        };
+
+        format!(
+            "{{\"change\":\"{}\",\"path\":{},\"old\":{},\"new\":{}}}",
+            change,
+            json_string(&format_path(path)),
+            old,
+            new
+        )
+    }
+}
+
+fn changes_to_json(changes: &[Change]) -> String {
+    changes_to_json_with(changes, &PUZZLE_OPERATORS)
+}
+
+fn changes_to_json_with(changes: &[Change], operators: &OperatorTable) -> String {
+    let changes: Vec<String> = changes.iter().map(|c| c.to_json_with(operators)).collect();
+    format!("[{}]", changes.join(","))
+}
+
+// Structural differences turning `old` into `new`, empty if the trees are equal
+fn diff(old: &Packet, new: &Packet) -> Vec<Change> {
+    let mut changes = vec![];
+    diff_at(old, new, &mut vec![], &mut changes);
+    changes
+}
+
+fn diff_at(old: &Packet, new: &Packet, path: &mut Vec<usize>, changes: &mut Vec<Change>) {
+    if old.literal_value().is_some() != new.literal_value().is_some() {
+        changes.push(Change::Replaced {
+            path: path.clone(),
+            old: old.clone(),
+            new: new.clone(),
+        });
+        return;
+    }
+
+    if old.version() != new.version() {
+        changes.push(Change::Version {
+            path: path.clone(),
+            old: old.version(),
+            new: new.version(),
+        });
+    }
+
+    match (old, new) {
+        (Packet::Literal { value: a, .. }, Packet::Literal { value: b, .. }) if a != b => {
+            changes.push(Change::Literal {
+                path: path.clone(),
+                old: *a,
+                new: *b,
+            });
+        }
+        (
+            Packet::Operator {
+                type_id: a_type,
+                length_type: a_length,
+                packets: a_packets,
+                ..
+            },
+            Packet::Operator {
+                type_id: b_type,
+                length_type: b_length,
+                packets: b_packets,
+                ..
+            },
+        ) => {
+            if a_type != b_type {
+                changes.push(Change::Operator {
+                    path: path.clone(),
+                    old: *a_type,
+                    new: *b_type,
+                });
+            }
+            if a_length != b_length {
+                changes.push(Change::LengthType {
+                    path: path.clone(),
+                    old: *a_length,
+                    new: *b_length,
+                });
+            }
+
+            diff_sub_packets(a_packets, b_packets, path, changes);
+        }
+        _ => {}
+    }
+}
+
+// Sub-packets equal in both trees anchor the alignment (longest common subsequence), the ones
+// between anchors are compared pairwise and the surplus is inserted or removed
+fn diff_sub_packets(
+    old: &[Packet],
+    new: &[Packet],
+    path: &mut Vec<usize>,
+    changes: &mut Vec<Change>,
+) {
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut anchors = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if old[i] == new[j] {
+            anchors.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    anchors.push((n, m));
+
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in anchors {
+        let paired = (next_i - i).min(next_j - j);
+        for k in 0..paired {
+            path.push(j + k);
+            diff_at(&old[i + k], &new[j + k], path, changes);
+            path.pop();
+        }
+        for (k, packet) in old[i + paired..next_i].iter().enumerate() {
+            path.push(i + paired + k);
+            changes.push(Change::Removed {
+                path: path.clone(),
+                packet: packet.clone(),
+            });
+            path.pop();
+        }
+        for (k, packet) in new[j + paired..next_j].iter().enumerate() {
+            path.push(j + paired + k);
+            changes.push(Change::Inserted {
+                path: path.clone(),
+                packet: packet.clone(),
+            });
+            path.pop();
+        }
+
+        i = next_i + 1;
+        j = next_j + 1;
+    }
+}
+
+// Path queries, e.g. `/0/2`, `//literal[value>100]` or `//op[type=product][version<3]`;
+// `//` alone selects every packet
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    // `//` matches the packet itself and everything below it, `/` only its sub-packets
+    descendants: bool,
+    test: Test,
+    predicates: Vec<Predicate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    Index(usize),
+    Literal,
+    Operator,
+    Any,
+}
+
+// Operator names are resolved to type ids when the query is parsed
+#[derive(Debug, Clone, PartialEq)]
+struct Predicate {
+    key: Key,
+    comparison: Comparison,
+    operand: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Value,
+    Version,
+    Type,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn holds(&self, a: u128, b: u128) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Query::parse_with(s, &PUZZLE_OPERATORS)
+    }
+}
+
+impl Query {
+    fn parse_with(s: &str, operators: &OperatorTable) -> Result<Query, String> {
+        let s = s.trim();
+        if !s.starts_with('/') {
+            return Err(format!("query {:?} must start with '/'", s));
+        }
+
+        let mut steps = vec![];
+        let mut rest = s;
+        while !rest.is_empty() {
+            let descendants = rest.starts_with("//");
+            rest = rest.trim_start_matches('/');
+
+            let end = rest.find(['/', '[']).unwrap_or(rest.len());
+            let test = match &rest[..end] {
+                "" if rest.is_empty() && !descendants => break,
+                "" if descendants => Test::Any,
+                "literal" => Test::Literal,
+                "op" => Test::Operator,
+                "*" => Test::Any,
+                name => Test::Index(
+                    name.parse()
+                        .map_err(|_| format!("unknown packet test {:?}", name))?,
+                ),
+            };
+            rest = &rest[end..];
+
+            let mut predicates = vec![];
+            while let Some(inner) = rest.strip_prefix('[') {
+                let close = inner.find(']').ok_or("unclosed '['")?;
+                predicates.push(Predicate::parse_with(&inner[..close], operators)?);
+                rest = &inner[close + 1..];
+            }
+
+            steps.push(Step {
+                descendants,
+                test,
+                predicates,
+            });
+        }
+
+        Ok(Query { steps })
+    }
+}
+
+impl Predicate {
+    fn parse_with(s: &str, operators: &OperatorTable) -> Result<Predicate, String> {
+        let start = s
+            .find(['=', '!', '<', '>'])
+            .ok_or(format!("predicate {:?} has no comparison", s))?;
+        let end = s[start..]
+            .find(|c| c != '=' && c != '!' && c != '<' && c != '>')
+            .map_or(s.len(), |i| start + i);
+
+        let key = match s[..start].trim() {
+            "value" => Key::Value,
+            "version" => Key::Version,
+            "type" => Key::Type,
+            key => return Err(format!("unknown key {:?}", key)),
+        };
+        let comparison = match &s[start..end] {
+            "=" | "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessOrEqual,
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterOrEqual,
+            comparison => return Err(format!("unknown comparison {:?}", comparison)),
+        };
+
+        // Operator names are accepted wherever a type id is
+        let operand = match (key, s[end..].trim()) {
+            (Key::Type, "literal") => 4,
+            (Key::Type, name) if operators.find(name).is_some() => {
+                operators.find(name).unwrap() as u128
+            }
+            (_, operand) => operand.parse().map_err(|_| match key {
+                Key::Type => format!("unknown packet type {:?}", operand),
+                _ => format!("{:?} is not a number", operand),
+            })?,
+        };
+
+        Ok(Predicate {
+            key,
+            comparison,
+            operand,
+        })
+    }
+}
+
+// A selected packet with its pre-order index, the same order `flatten` and the tracer use
+#[derive(Debug, Clone, PartialEq)]
+struct Match<'a> {
+    path: Vec<usize>,
+    index: usize,
+    packet: &'a Packet,
+}
+
+impl Predicate {
+    fn matches(&self, packet: &Packet, operators: &OperatorTable) -> bool {
+        let actual = match self.key {
+            Key::Value => packet.checked_eval_with(operators),
+            Key::Version => Some(packet.version() as u128),
+            Key::Type => Some(packet.type_id() as u128),
+        };
+        actual.is_some_and(|a| self.comparison.holds(a, self.operand))
+    }
+}
+
+impl Query {
+    fn select<'a>(&self, root: &'a Packet) -> Vec<Match<'a>> {
+        self.select_with(root, &PUZZLE_OPERATORS)
+    }
+
+    fn select_with<'a>(&self, root: &'a Packet, operators: &OperatorTable) -> Vec<Match<'a>> {
+        let mut current = vec![Match {
+            path: vec![],
+            index: 0,
+            packet: root,
+        }];
+
+        for step in &self.steps {
+            let mut candidates = vec![];
+            for m in &current {
+                if step.descendants {
+                    collect_descendants(m, &mut candidates);
+                } else {
+                    candidates.extend(sub_matches(m));
+                }
+            }
+
+            current = candidates
+                .into_iter()
+                .filter(|m| match (&step.test, m.packet) {
+                    (Test::Index(i), _) => m.path.last() == Some(i),
+                    (Test::Literal, Packet::Literal { .. }) => true,
+                    (Test::Operator, Packet::Operator { .. }) => true,
+                    (Test::Any, _) => true,
+                    _ => false,
+                })
+                .filter(|m| {
+                    step.predicates
+                        .iter()
+                        .all(|p| p.matches(m.packet, operators))
+                })
+                .collect();
+
+            // `//` from several nested packets reaches some packets more than once
+            current.sort_by_key(|m| m.index);
+            current.dedup_by_key(|m| m.index);
+        }
+
+        current
+    }
+}
+
+fn sub_matches<'a>(parent: &Match<'a>) -> Vec<Match<'a>> {
+    let packets = match parent.packet {
+        Packet::Literal { .. } => return vec![],
+        Packet::Operator { packets, .. } => packets,
+    };
+
+    let mut index = parent.index + 1;
+    let mut result = vec![];
+    for (i, packet) in packets.iter().enumerate() {
+        let mut path = parent.path.clone();
+        path.push(i);
+        result.push(Match {
+            path,
+            index,
+            packet,
+        });
+        index += packet.flatten().len();
+    }
+
+    result
+}
+
+fn collect_descendants<'a>(m: &Match<'a>, result: &mut Vec<Match<'a>>) {
+    result.push(m.clone());
+    for sub in sub_matches(m) {
+        collect_descendants(&sub, result);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum DecodeError {
+    InvalidDigit { position: usize, digit: char },
+    UnexpectedEnd,
+    LiteralOverflow,
+    Overrun { end: usize, position: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidDigit { position, digit } => {
+                write!(f, "invalid digit {:?} at position {}", digit, position)
+            }
+            DecodeError::UnexpectedEnd => write!(f, "transmission ended in the middle of a packet"),
+            DecodeError::LiteralOverflow => write!(f, "literal value does not fit in 128 bits"),
+            DecodeError::Overrun { end, position } => write!(
+                f,
+                "sub-packets end at bit {}, past their operator's length ending at {}",
+                position, end
+            ),
+        }
+    }
+}
+
+// The fixed-width fields a packet is made of
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Version,
+    TypeId,
+    LiteralGroup,
+    LengthType,
+    TotalBits,
+    SubPacketCount,
+}
+
+impl Field {
+    fn width(&self) -> usize {
+        match self {
+            Field::Version | Field::TypeId => 3,
+            Field::LiteralGroup => 5,
+            Field::LengthType => 1,
+            Field::TotalBits => LengthType::TotalBits.field_width(),
+            Field::SubPacketCount => LengthType::SubPacketCount.field_width(),
+        }
+    }
+}
+
+// Positions are bit offsets from the start of the transmission, depth 0 is the outermost packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TraceEvent {
+    PacketStart {
+        position: usize,
+        depth: usize,
+    },
+    Field {
+        field: Field,
+        position: usize,
+        width: usize,
+        value: u128,
+        depth: usize,
+    },
+    PacketEnd {
+        position: usize,
+        depth: usize,
+    },
+}
+
+trait Tracer {
+    fn event(&mut self, event: TraceEvent);
+}
+
+// Default for `read_packet`, compiles down to nothing
+struct NoTrace;
+
+impl Tracer for NoTrace {
+    #[inline(always)]
+    fn event(&mut self, _: TraceEvent) {}
+}
+
+// Structured events, in reading order
+impl Tracer for Vec<TraceEvent> {
+    fn event(&mut self, event: TraceEvent) {
+        self.push(event);
+    }
+}
+
+// Indented, human readable trace
+#[cfg(feature = "std")]
+struct TextTrace<W: Write> {
+    out: W,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Tracer for TextTrace<W> {
+    fn event(&mut self, event: TraceEvent) {
+        // Tracing is a debugging aid, a broken pipe should not abort the decode
+        let _ = match event {
+            TraceEvent::PacketStart { position, depth } => {
+                writeln!(
+                    self.out,
+                    "{:>6} {:indent$}packet",
+                    position,
+                    "",
+                    indent = depth * 2
+                )
+            }
+            TraceEvent::Field {
+                field,
+                position,
+                width,
+                value,
+                depth,
+            } => writeln!(
+                self.out,
+                "{:>6} {:indent$}  {:<16} {:>2} bits = {}",
+                position,
+                "",
+                format!("{:?}", field),
+                width,
+                value,
+                indent = depth * 2
+            ),
+            TraceEvent::PacketEnd { .. } => Ok(()),
+        };
+    }
+}
+
+// Bit range `start..end` of every packet, in pre-order
+#[derive(Default)]
+struct SpanTrace {
+    spans: Vec<(usize, usize)>,
+    open: Vec<usize>,
+}
+
+impl Tracer for SpanTrace {
+    fn event(&mut self, event: TraceEvent) {
+        match event {
+            TraceEvent::PacketStart { position, .. } => {
+                self.open.push(self.spans.len());
+                self.spans.push((position, position));
+            }
+            TraceEvent::PacketEnd { position, .. } => {
+                if let Some(i) = self.open.pop() {
+                    self.spans[i].1 = position;
+                }
+            }
+            TraceEvent::Field { .. } => {}
+        }
+    }
+}
+
+type BitIterator = dyn Iterator<Item = Bit>;
+
+struct BitStream {
+    iter: Peekable<Box<BitIterator>>,
+    position: usize,
+}
+
+impl BitStream {
+    fn from_iter<I: 'static + Iterator<Item = Bit>>(iter: I) -> BitStream {
+        let iter: Box<BitIterator> = Box::new(iter);
+        BitStream {
+            iter: iter.peekable(),
+            position: 0,
+        }
+    }
+
+    fn from_vec(data: Vec<Bit>) -> BitStream {
+        BitStream::from_iter(data.into_iter())
+    }
+
+    fn from_radix_str(data: &str, radix: u32) -> Result<BitStream, DecodeError> {
+        let width = radix.trailing_zeros();
+        let mut bits = vec![];
+
+        for (position, digit) in data.trim().chars().enumerate() {
+            let val = digit
+                .to_digit(radix)
+                .ok_or(DecodeError::InvalidDigit { position, digit })?;
+            bits.extend((0..width).rev().map(|i| ((val >> i) & 1) as Bit));
+        }
+
+        Ok(BitStream::from_vec(bits))
+    }
+
+    fn from_hex_str(data: &str) -> Result<BitStream, DecodeError> {
+        BitStream::from_radix_str(data, 16)
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn from_binary_str(data: &str) -> Result<BitStream, DecodeError> {
+        BitStream::from_radix_str(data, 2)
+    }
+
+    // Most significant bit of each byte first, same as two hex digits
+    fn from_bytes(data: &[u8]) -> BitStream {
+        let mut bits = Vec::with_capacity(data.len() * 8);
+        for byte in data {
+            push_bits(&mut bits, *byte as u128, 8);
+        }
+
+        BitStream::from_vec(bits)
+    }
+
+    #[cfg(feature = "std")]
+    fn from_reader<R: Read>(mut reader: R) -> io::Result<BitStream> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(BitStream::from_bytes(&data))
+    }
+
+    // Standard alphabet, padding optional, whitespace (e.g. line breaks) ignored
+    fn from_base64_str(data: &str) -> Result<BitStream, DecodeError> {
+        let mut bits = vec![];
+        let mut padded = false;
+
+        for (position, digit) in data.chars().enumerate() {
+            let val = match digit {
+                'A'..='Z' => digit as u32 - 'A' as u32,
+                'a'..='z' => digit as u32 - 'a' as u32 + 26,
+                '0'..='9' => digit as u32 - '0' as u32 + 52,
+                '+' => 62,
+                '/' => 63,
+                '=' => {
+                    padded = true;
+                    continue;
+                }
+                c if c.is_ascii_whitespace() => continue,
+                _ => return Err(DecodeError::InvalidDigit { position, digit }),
+            };
+
+            if padded {
+                return Err(DecodeError::InvalidDigit { position, digit });
+            }
+            push_bits(&mut bits, val as u128, 6);
+        }
+
+        // Bits that do not complete a byte are only there to fill the last digit
+        bits.truncate(bits.len() / 8 * 8);
+        Ok(BitStream::from_vec(bits))
+    }
+
+    // Everything after the last packet read, i.e. the padding
+    fn remaining(&mut self) -> Vec<Bit> {
+        self.iter.by_ref().collect()
+    }
+
+    fn read_bits(&mut self, n: usize) -> Result<u128, DecodeError> {
+        let mut result = 0;
+        for _ in 0..n {
+            let bit = self.iter.next().ok_or(DecodeError::UnexpectedEnd)?;
+            result = (result << 1) + bit as u128;
+            self.position += 1;
+        }
+
+        Ok(result)
+    }
+
+    fn read_field<T: Tracer>(
+        &mut self,
+        tracer: &mut T,
+        field: Field,
+        depth: usize,
+    ) -> Result<u128, DecodeError> {
+        let position = self.position;
+        let width = field.width();
+        let value = self.read_bits(width)?;
+
+        tracer.event(TraceEvent::Field {
+            field,
+            position,
+            width,
+            value,
+            depth,
+        });
+        Ok(value)
+    }
+
+    fn read_packet(&mut self) -> Result<Packet, DecodeError> {
+        self.read_packet_traced(&mut NoTrace)
+    }
+
+    // `read_packet`, reporting every field it reads to `tracer`
+    fn read_packet_traced<T: Tracer>(&mut self, tracer: &mut T) -> Result<Packet, DecodeError> {
+        self.read_packet_at(tracer, 0)
+    }
+
+    fn read_packet_at<T: Tracer>(
+        &mut self,
+        tracer: &mut T,
+        depth: usize,
+    ) -> Result<Packet, DecodeError> {
+        tracer.event(TraceEvent::PacketStart {
+            position: self.position,
+            depth,
+        });
+
+        let version = self.read_field(tracer, Field::Version, depth)? as u8;
+        let type_id = self.read_field(tracer, Field::TypeId, depth)? as u8;
+
+        let result = match type_id {
+            4 => Packet::Literal {
+                version,
+                type_id,
+                value: self.read_literal_value(tracer, depth)?,
+            },
+            _ => {
+                let (length_type, length) = self.read_length(tracer, depth)?;
+
+                let sub_packets: Vec<Packet> = match length_type {
+                    LengthType::SubPacketCount => (0..length)
+                        .map(|_| self.read_packet_at(tracer, depth + 1))
+                        .collect::<Result<_, _>>()?,
+                    LengthType::TotalBits => {
+                        let end = self.position + length;
+
+                        let mut result = vec![];
+                        while self.position < end {
+                            result.push(self.read_packet_at(tracer, depth + 1)?);
+                        }
+
+                        if self.position > end {
+                            return Err(DecodeError::Overrun {
+                                end,
+                                position: self.position,
+                            });
+                        }
+
+                        result
+                    }
+                };
+
+                Packet::Operator {
+                    version,
+                    type_id,
+                    length_type,
+                    packets: sub_packets,
+                }
+            }
+        };
+
+        tracer.event(TraceEvent::PacketEnd {
+            position: self.position,
+            depth,
+        });
+        Ok(result)
+    }
+
+    fn read_literal_value<T: Tracer>(
+        &mut self,
+        tracer: &mut T,
+        depth: usize,
+    ) -> Result<u128, DecodeError> {
+        let mut value: u128 = 0;
+        loop {
+            let group = self.read_field(tracer, Field::LiteralGroup, depth)?;
+            if value >> 124 != 0 {
+                return Err(DecodeError::LiteralOverflow);
+            }
+
+            value = (value << 4) + (group & 0xF);
+            if group >> 4 & 1 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_length<T: Tracer>(
+        &mut self,
+        tracer: &mut T,
+        depth: usize,
+    ) -> Result<(LengthType, usize), DecodeError> {
+        match self.read_field(tracer, Field::LengthType, depth)? {
+            1 => {
+                let count = self.read_field(tracer, Field::SubPacketCount, depth)?;
+                Ok((LengthType::SubPacketCount, count as usize))
+            }
+            _ => {
+                let bits = self.read_field(tracer, Field::TotalBits, depth)?;
+                Ok((LengthType::TotalBits, bits as usize))
+            }
+        }
+    }
+
+    // `read_packet` + `checked_eval` + `version_sum` without building the tree
+    fn eval_single_pass(&mut self) -> Result<Evaluation, DecodeError> {
+        self.eval_single_pass_with(&PUZZLE_OPERATORS)
+    }
+
+    fn eval_single_pass_with(
+        &mut self,
+        operators: &OperatorTable,
+    ) -> Result<Evaluation, DecodeError> {
+        let mut version_sum = 0;
+        let mut open: Vec<OpenOperator> = vec![];
+
+        loop {
+            version_sum += self.read_bits(3)?;
+            let type_id = self.read_bits(3)? as u8;
+
+            // `None` while the packet just read is an operator still waiting for its sub-packets
+            let mut done = match type_id {
+                4 => Some(Some(self.read_literal_value(&mut NoTrace, 0)?)),
+                _ => {
+                    let (length_type, length) = self.read_length(&mut NoTrace, 0)?;
+                    let end = match length_type {
+                        LengthType::TotalBits => End::Position(self.position + length),
+                        LengthType::SubPacketCount => End::Count(length),
+                    };
+                    open.push(OpenOperator::new(operators.get(type_id).copied(), end));
+                    None
+                }
+            };
+
+            loop {
+                let current = match (open.last_mut(), done.take()) {
+                    (None, value) => {
+                        return Ok(Evaluation {
+                            value: value.flatten(),
+                            version_sum,
+                        })
+                    }
+                    (Some(current), value) => {
+                        if let Some(value) = value {
+                            current.push(value);
+                        }
+                        current
+                    }
+                };
+
+                let finished = match current.end {
+                    End::Count(count) => current.seen >= count,
+                    End::Position(end) if self.position > end => {
+                        return Err(DecodeError::Overrun {
+                            end,
+                            position: self.position,
+                        })
+                    }
+                    End::Position(end) => self.position == end,
+                };
+                if !finished {
+                    break;
+                }
+
+                done = open.pop().map(|current| current.finish());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Evaluation {
+    value: Option<u128>,
+    version_sum: u128,
+}
+
+enum End {
+    Position(usize),
+    Count(usize),
+}
+
+// What `eval_single_pass` keeps of an operator whose sub-packets are still being read
+enum Accumulator {
+    Empty,
+    Value(u128),
+    // The absorbing operand may still turn up and decide the result
+    Overflow,
+    Absorbed(u128),
+    // Non-associative operators, bounded by their arity
+    Operands(Vec<u128>),
+    Failed,
+}
+
+struct OpenOperator {
+    operator: Option<OperatorDef>,
+    end: End,
+    seen: usize,
+    accumulator: Accumulator,
+}
+
+impl OpenOperator {
+    fn new(operator: Option<OperatorDef>, end: End) -> OpenOperator {
+        let accumulator = match operator {
+            Some(operator) if !operator.associative => Accumulator::Operands(vec![]),
+            Some(_) => Accumulator::Empty,
+            None => Accumulator::Failed,
+        };
+
+        OpenOperator {
+            operator,
+            end,
+            seen: 0,
+            accumulator,
+        }
+    }
+
+    fn push(&mut self, value: Option<u128>) {
+        self.seen += 1;
+
+        let (operator, value) = match (self.operator, value) {
+            (Some(operator), Some(value)) => (operator, value),
+            _ => {
+                self.accumulator = Accumulator::Failed;
+                return;
+            }
+        };
+
+        self.accumulator = match core::mem::replace(&mut self.accumulator, Accumulator::Failed) {
+            Accumulator::Failed => Accumulator::Failed,
+            Accumulator::Operands(mut operands) => {
+                // Operands past an exact arity fail `finish` anyway, no need to keep them
+                match operator.arity {
+                    Arity::Exactly(n) if operands.len() >= n => {}
+                    _ => operands.push(value),
+                }
+                Accumulator::Operands(operands)
+            }
+            Accumulator::Absorbed(a) => Accumulator::Absorbed(a),
+            _ if operator.absorbing == Some(value) => Accumulator::Absorbed(value),
+            Accumulator::Empty => Accumulator::Value(value),
+            Accumulator::Value(a) => match (operator.eval)(&[a, value]) {
+                Some(a) => Accumulator::Value(a),
+                None => Accumulator::Overflow,
+            },
+            Accumulator::Overflow => Accumulator::Overflow,
+        };
+    }
+
+    fn finish(self) -> Option<u128> {
+        let operator = self.operator?;
+        if !operator.arity.accepts(self.seen) {
+            return None;
+        }
+
+        match self.accumulator {
+            Accumulator::Empty => (operator.eval)(&[]),
+            Accumulator::Value(a) | Accumulator::Absorbed(a) => Some(a),
+            Accumulator::Operands(operands) => (operator.eval)(&operands),
+            Accumulator::Overflow | Accumulator::Failed => None,
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// One JSON object per transmission: version sum, value and rule violations, or the decode error
+fn decode_line(line_no: usize, line: &str) -> String {
+    let decoded = BitStream::from_hex_str(line).and_then(|mut stream| {
+        let packet = stream.read_packet()?;
+        Ok((packet, stream.remaining()))
+    });
+
+    let (packet, trailing) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            return format!(
+                "{{\"line\":{},\"error\":{}}}",
+                line_no,
+                json_string(&e.to_string())
+            )
+        }
+    };
+
+    let issues: Vec<String> = packet
+        .validate()
+        .iter()
+        .chain(validate_padding(&trailing).iter())
+        .map(|issue| json_string(&issue.to_string()))
+        .collect();
+
+    // u128 values as decimal strings, like the serde form
+    let value = match packet.checked_eval() {
+        Some(value) => json_string(&value.to_string()),
+        None => "null".to_string(),
+    };
+
+    format!(
+        "{{\"line\":{},\"version_sum\":{},\"value\":{},\"issues\":[{}]}}",
+        line_no,
+        json_string(&packet.version_sum().to_string()),
+        value,
+        issues.join(",")
+    )
+}
+
+#[cfg(feature = "std")]
+fn decode_stream<R: BufRead, W: Write>(input: R, mut output: W) -> io::Result<()> {
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        writeln!(output, "{}", decode_line(i + 1, &line))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+fn usage(error: &str) -> ! {
+    eprintln!("day16: {}", error);
+    eprintln!(
+        "Usage: day16 [--trace | --single-pass | --query QUERY] [--raw FILE | --base64 FILE]"
+    );
+    std::process::exit(2)
+}
+
+#[cfg(feature = "std")]
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Usage: day16 --stream [FILE], one hex transmission per line (stdin by default)
+    if args.get(1).map(String::as_str) == Some("--stream") {
+        let stdout = io::stdout();
+        let result = match args.get(2) {
+            Some(path) => decode_stream(BufReader::new(File::open(path).unwrap()), stdout.lock()),
+            None => decode_stream(io::stdin().lock(), stdout.lock()),
+        };
+
+        result.unwrap();
+        return;
+    }
+
+    // Usage: day16 --diff HEX HEX [--json]
+    if args.get(1).map(String::as_str) == Some("--diff") {
+        let decode = |hex: &str| BitStream::from_hex_str(hex).unwrap().read_packet().unwrap();
+        let changes = diff(&decode(&args[2]), &decode(&args[3]));
+
+        if args.get(4).map(String::as_str) == Some("--json") {
+            println!("{}", changes_to_json(&changes));
+        } else {
+            for change in &changes {
+                println!("{}", change);
+            }
+        }
+        return;
+    }
+
+    // Usage: day16 [--trace | --single-pass | --query QUERY] [--raw FILE | --base64 FILE]
+    // Hex from input-day-16.txt by default
+    let mut trace = false;
+    let mut single_pass = false;
+    let mut query = None;
+    let mut input = vec![];
+
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--single-pass" => single_pass = true,
+            "--query" => match rest.next().map(|q| q.parse::<Query>()) {
+                Some(Ok(parsed)) => query = Some(parsed),
+                Some(Err(e)) => usage(&e),
+                None => usage("--query needs a query, e.g. '//literal[value>100]'"),
+            },
+            _ => input.push(arg.as_str()),
+        }
+    }
+
+    let mut stream = match (input.first().copied(), input.get(1)) {
+        (Some("--raw"), Some(path)) => BitStream::from_reader(File::open(path).unwrap()).unwrap(),
+        (Some("--base64"), Some(path)) => {
+            BitStream::from_base64_str(&fs::read_to_string(path).unwrap()).unwrap()
+        }
+        _ => {
+            let data = fs::read_to_string("input-day-16.txt").unwrap();
+
+            // let data = "110100101111111000101000";
+            // let data = "00111000000000000110111101000101001010010001001000000000";
+            // let data = "11101110000000001101010000001100100000100011000001100000";
+
+            BitStream::from_hex_str(&data).unwrap()
+        }
+    };
+
+    if single_pass {
+        let evaluation = stream.eval_single_pass().unwrap();
+        println!("Task 1: {}", evaluation.version_sum);
+        match evaluation.value {
+            Some(value) => println!("Task 2: {}", value),
+            None => println!("Task 2: packet is invalid or its value overflows"),
+        }
+        return;
+    }
+
+    if let Some(query) = query {
+        let mut spans = SpanTrace::default();
+        let packet = stream.read_packet_traced(&mut spans).unwrap();
+
+        for m in query.select(&packet) {
+            let (start, end) = spans.spans[m.index];
+            let value = match m.packet.checked_eval() {
+                Some(value) => value.to_string(),
+                None => "-".to_string(),
+            };
+            println!(
+                "{} version={} value={} bits={}..{}",
+                format_path(&m.path),
+                m.packet.version(),
+                value,
+                start,
+                end
+            );
+        }
+        return;
+    }
+
+    let packet = if trace {
+        stream
+            .read_packet_traced(&mut TextTrace { out: io::stderr() })
+            .unwrap()
+    } else {
+        stream.read_packet().unwrap()
+    };
+
+    let trailing = stream.remaining();
+
+    println!("{:?}", packet);
+    println!("Re-encoded: {}", packet.to_hex());
+
+    #[cfg(feature = "serde")]
+    println!("{}", serde_json::to_string(&packet).unwrap());
+
+    let issues: Vec<Issue> = packet
+        .validate()
+        .into_iter()
+        .chain(validate_padding(&trailing))
+        .collect();
+    for issue in &issues {
+        println!("Warning: {}", issue);
+    }
+
+    println!("Task 1: {}", packet.version_sum());
+    println!("Task 2: {}", packet.eval());
+
+    let simplified = packet.simplify().with_shortest_lengths();
+    println!("Simplified: {}", simplified);
+    println!(
+        "Size: {} bits -> {} bits ({})",
+        packet.bit_len(),
+        simplified.bit_len(),
+        simplified.to_hex()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The puzzle's example transmissions: hex, version sum, value
+    const EXAMPLES: [(&str, u128, u128); 15] = [
+        ("D2FE28", 6, 2021),
+        ("38006F45291200", 9, 1),
+        ("EE00D40C823060", 14, 3),
+        ("8A004A801A8002F478", 16, 15),
+        ("620080001611562C8802118E34", 12, 46),
+        ("C0015000016115A2E0802F182340", 23, 46),
+        ("A0016C880162017C3686B18A3D4780", 31, 54),
+        ("C200B40A82", 14, 3),
+        ("04005AC33890", 8, 54),
+        ("880086C3E88112", 15, 7),
+        ("CE00C43D881120", 11, 9),
+        ("D8005AC2A8F0", 13, 1),
+        ("F600BC2D8F", 19, 0),
+        ("9C005AC2F8F0", 16, 0),
+        ("9C0141080250320F1802104A08", 20, 1),
+    ];
+
+    fn decode(hex: &str) -> Packet {
+        BitStream::from_hex_str(hex).unwrap().read_packet().unwrap()
+    }
+
+    // The original hex, apart from the zero padding after the packet
+    fn assert_reencodes(packet: &Packet, hex: &str) {
+        let reencoded = packet.to_hex();
+        assert!(
+            hex.starts_with(&reencoded),
+            "{} re-encoded as {}",
+            hex,
+            reencoded
+        );
+        assert!(hex[reencoded.len()..].chars().all(|c| c == '0'), "{}", hex);
+    }
+
+    fn literal(value: u128) -> Packet {
+        Packet::Literal {
+            version: 0,
+            type_id: 4,
+            value,
+        }
+    }
+
+    fn operator(type_id: u8, packets: Vec<Packet>) -> Packet {
+        Packet::Operator {
+            version: 0,
+            type_id,
+            length_type: LengthType::SubPacketCount,
+            packets,
+        }
+    }
+
+    #[test]
+    fn simplify_keeps_invalid_and_overflowing_values() {
+        let overflowing = operator(1, vec![literal(u128::MAX), literal(2)]);
+        for packet in [
+            operator(1, vec![literal(0), operator(0, vec![])]),
+            operator(1, vec![overflowing.clone(), literal(0)]),
+            operator(2, vec![literal(0), operator(5, vec![literal(1)])]),
+            operator(0, vec![literal(1), operator(0, vec![])]),
+        ] {
+            assert_eq!(packet.checked_eval(), None, "{}", packet);
+            assert_eq!(packet.simplify().checked_eval(), None, "{}", packet);
+        }
+
+        let packet = operator(
+            1,
+            vec![literal(0), operator(0, vec![literal(3), literal(4)])],
+        );
+        assert_eq!(packet.simplify(), literal(0));
+        let packet = operator(
+            0,
+            vec![literal(1), operator(0, vec![literal(2), overflowing])],
+        );
+        assert_eq!(
+            packet.simplify().to_string(),
+            "sum(1, sum(2, product(340282366920938463463374607431768211455, 2)))"
+        );
+        let packet = operator(
+            0,
+            vec![literal(1), operator(0, vec![literal(2), literal(3)])],
+        );
+        assert_eq!(packet.simplify(), literal(6));
+        assert!(packet.simplify().bit_len() < packet.bit_len());
+
+        // Operands that never change the result go, the overflow stays
+        let packet = operator(
+            0,
+            vec![
+                literal(u128::MAX),
+                literal(0),
+                operator(2, vec![literal(1)]),
+            ],
+        );
+        assert_eq!(
+            packet.simplify().to_string(),
+            "sum(340282366920938463463374607431768211455, 1)"
+        );
+    }
+
+    #[test]
+    fn product_with_zero_factor_is_zero() {
+        let max = || literal(u128::MAX);
+        for packet in [
+            operator(1, vec![max(), max(), literal(0)]),
+            operator(1, vec![literal(0), max(), max()]),
+        ] {
+            assert_eq!(packet.checked_eval(), Some(0), "{}", packet);
+            let single_pass = BitStream::from_vec(packet.to_bits()).eval_single_pass();
+            assert_eq!(single_pass.unwrap().value, Some(0), "{}", packet);
+        }
+        assert_eq!(operator(1, vec![max(), max()]).checked_eval(), None);
+    }
+
+    fn eval_xor(values: &[u128]) -> Option<u128> {
+        Some(values.iter().fold(0, |a, b| a ^ b))
+    }
+
+    const XOR: OperatorDef = OperatorDef {
+        name: "xor",
+        arity: Arity::AtLeast(2),
+        eval: eval_xor,
+        associative: true,
+        identity: Some(0),
+        absorbing: None,
+    };
+
+    #[test]
+    fn custom_operator() {
+        let operators = PUZZLE_OPERATORS.with(5, XOR);
+        let packet = operator(
+            5,
+            vec![literal(6), operator(5, vec![literal(3), literal(0)])],
+        );
+
+        assert_eq!(packet.checked_eval_with(&operators), Some(5));
+        assert_eq!(packet.checked_eval(), Some(1));
+        assert_eq!(
+            packet.display_with(&operators).to_string(),
+            "xor(6, xor(3, 0))"
+        );
+        assert_eq!(packet.to_string(), "greater_than(6, greater_than(3, 0))");
+
+        let mut stream = BitStream::from_vec(packet.to_bits());
+        let evaluation = stream.eval_single_pass_with(&operators).unwrap();
+        assert_eq!(evaluation.value, Some(5));
+
+        assert_eq!(packet.validate_with(&operators), vec![]);
+        let unary = operator(5, vec![literal(1)]);
+        assert_eq!(
+            unary.validate_with(&operators),
+            vec![Issue {
+                path: vec![],
+                violation: Violation::WrongArity {
+                    type_id: 5,
+                    expected: Arity::AtLeast(2),
+                    found: 1,
+                },
+            }]
+        );
+        assert_eq!(unary.checked_eval_with(&operators), None);
+
+        // One operand is too few for xor, and simplifying must not hide that
+        let unary = operator(5, vec![literal(7)]);
+        assert_eq!(unary.simplify_with(&operators), unary);
+        let packet = operator(0, vec![unary.clone(), literal(1)]);
+        assert_eq!(
+            packet
+                .simplify_with(&operators)
+                .checked_eval_with(&operators),
+            None
+        );
+        let packet = operator(5, vec![literal(7), literal(0), operator(5, vec![])]);
+        assert_eq!(
+            packet
+                .simplify_with(&operators)
+                .display_with(&operators)
+                .to_string(),
+            "xor(7, xor())"
+        );
+
+        // Diffs name operators and packets after the same table
+        let old = operator(5, vec![literal(1), literal(2)]);
+        let new = operator(
+            0,
+            vec![literal(1), operator(5, vec![literal(2), literal(3)])],
+        );
+        let changes = diff(&old, &new);
+        let text: Vec<String> = changes
+            .iter()
+            .map(|c| c.display_with(&operators).to_string())
+            .collect();
+        assert_eq!(text, ["~ / operator xor -> sum", "! /1 2 -> xor(2, 3)"]);
+        assert_eq!(
+            changes_to_json_with(&changes, &operators),
+            concat!(
+                r#"[{"change":"operator","path":"/","old":"xor","new":"sum"},"#,
+                r#"{"change":"replaced","path":"/1","old":"2","new":"xor(2, 3)"}]"#
+            )
+        );
+
+        // Only xor is known, the puzzle's operators are not
+        let operators = OperatorTable::empty().with(0, XOR);
+        let packet = operator(0, vec![literal(1), operator(1, vec![literal(2)])]);
+        assert_eq!(
+            packet.display_with(&operators).to_string(),
+            "xor(1, op1(2))"
+        );
+        assert_eq!(packet.checked_eval_with(&operators), None);
+        assert_eq!(packet.validate_with(&operators).len(), 1);
+    }
+
+    #[test]
+    fn validate_reports_every_violation() {
+        for (hex, _, _) in EXAMPLES {
+            assert_eq!(decode(hex).validate(), vec![], "{}", hex);
+        }
+
+        let packet = operator(
+            0,
+            vec![
+                literal(1),
+                operator(5, vec![literal(1), literal(2), literal(3)]),
+                operator(2, vec![]),
+                Packet::Literal {
+                    version: 8,
+                    type_id: 3,
+                    value: 0,
+                },
+            ],
+        );
+        let issues: Vec<String> = packet.validate().iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            issues,
+            [
+                "/1: operator 5 expects exactly 2 sub-packets, found 3",
+                "/2: operator 2 expects at least 1 sub-packets, found 0",
+                "/3: version 8 does not fit in 3 bits",
+                "/3: type id 3 is reserved for this packet kind",
+            ]
+        );
+        assert_eq!(packet.checked_eval(), None);
+    }
+
+    #[test]
+    fn validate_padding_after_the_packet() {
+        // D2FE28 with a stray one bit after the usual padding
+        let mut stream = BitStream::from_binary_str("1101001011111110001010001").unwrap();
+        assert_eq!(stream.read_packet().unwrap(), decode("D2FE28"));
+        let issues: Vec<String> = validate_padding(&stream.remaining())
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                "/: padding contains non-zero bits",
+                "/: 4 unused bits after the outermost packet",
+            ]
+        );
+
+        let mut stream = BitStream::from_hex_str("D2FE28").unwrap();
+        stream.read_packet().unwrap();
+        assert_eq!(validate_padding(&stream.remaining()), vec![]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_stream_one_line_per_transmission() {
+        let input = "D2FE28\n\n9C0141080250320F1802104A08\nXYZ\nD2FE29\n38006F45291200\n";
+        let mut output = vec![];
+        decode_stream(input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"line":1,"version_sum":"6","value":"2021","issues":[]}"#,
+                "\n",
+                r#"{"line":3,"version_sum":"20","value":"1","issues":[]}"#,
+                "\n",
+                r#"{"line":4,"error":"invalid digit 'X' at position 0"}"#,
+                "\n",
+                r#"{"line":5,"version_sum":"6","value":"2021","issues":["/: padding contains non-zero bits"]}"#,
+                "\n",
+                r#"{"line":6,"version_sum":"9","value":"1","issues":["/: 7 unused bits after the outermost packet"]}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn trace_events() {
+        let mut events = vec![];
+        let packet = BitStream::from_hex_str("D2FE28")
+            .unwrap()
+            .read_packet_traced(&mut events)
+            .unwrap();
+        assert_eq!(packet, decode("D2FE28"));
+
+        let field = |field, position, width, value| TraceEvent::Field {
+            field,
+            position,
+            width,
+            value,
+            depth: 0,
+        };
+        assert_eq!(
+            events,
+            [
+                TraceEvent::PacketStart {
+                    position: 0,
+                    depth: 0
+                },
+                field(Field::Version, 0, 3, 6),
+                field(Field::TypeId, 3, 3, 4),
+                field(Field::LiteralGroup, 6, 5, 0b10111),
+                field(Field::LiteralGroup, 11, 5, 0b11110),
+                field(Field::LiteralGroup, 16, 5, 0b00101),
+                TraceEvent::PacketEnd {
+                    position: 21,
+                    depth: 0
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn trace_text() {
+        let mut trace = TextTrace { out: vec![] };
+        BitStream::from_hex_str("38006F45291200")
+            .unwrap()
+            .read_packet_traced(&mut trace)
+            .unwrap();
+        let expected = [
+            "     0 packet",
+            "     0   Version           3 bits = 1",
+            "     3   TypeId            3 bits = 6",
+            "     6   LengthType        1 bits = 0",
+            "     7   TotalBits        15 bits = 27",
+            "    22   packet",
+            "    22     Version           3 bits = 6",
+            "    25     TypeId            3 bits = 4",
+            "    28     LiteralGroup      5 bits = 10",
+            "    33   packet",
+            "    33     Version           3 bits = 2",
+            "    36     TypeId            3 bits = 4",
+            "    39     LiteralGroup      5 bits = 17",
+            "    44     LiteralGroup      5 bits = 4",
+        ];
+        let text = String::from_utf8(trace.out).unwrap();
+        assert_eq!(text.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn single_pass_matches_tree() {
+        let invalid = operator(
+            0,
+            vec![operator(6, vec![literal(1)]), operator(3, vec![literal(2)])],
+        );
+        let overflowing = operator(0, vec![literal(u128::MAX), literal(1)]);
+        let transmissions = EXAMPLES
+            .iter()
+            .map(|(hex, _, _)| hex.to_string())
+            .chain([invalid.to_hex(), overflowing.to_hex()]);
+
+        for hex in transmissions {
+            let packet = decode(&hex);
+            let evaluation = BitStream::from_hex_str(&hex)
+                .unwrap()
+                .eval_single_pass()
+                .unwrap();
+            assert_eq!(
+                evaluation,
+                Evaluation {
+                    value: packet.checked_eval(),
+                    version_sum: packet.version_sum(),
+                },
+                "{}",
+                hex
+            );
+        }
+        assert_eq!(invalid.checked_eval(), None);
+        assert_eq!(overflowing.checked_eval(), None);
+
+        // Truncated transmissions fail the same way
+        for hex in ["38006F4529", "8A004A80", "D2F"] {
+            assert_eq!(
+                BitStream::from_hex_str(hex).unwrap().eval_single_pass(),
+                Err(BitStream::from_hex_str(hex)
+                    .unwrap()
+                    .read_packet()
+                    .unwrap_err()),
+                "{}",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn diff_puzzle_examples() {
+        assert_eq!(diff(&decode("C200B40A82"), &decode("C200B40A82")), vec![]);
+
+        let changes = diff(&decode("C200B40A82"), &decode("04005AC33890"));
+        let text: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            text,
+            [
+                "~ / version 6 -> 0",
+                "~ / operator sum -> product",
+                "~ / length type SubPacketCount -> TotalBits",
+                "~ /0 version 6 -> 5",
+                "~ /0 literal 1 -> 6",
+                "~ /1 version 2 -> 3",
+                "~ /1 literal 2 -> 9",
+            ]
+        );
+        assert_eq!(
+            changes_to_json(&changes[..2]),
+            concat!(
+                r#"[{"change":"version","path":"/","old":6,"new":0},"#,
+                r#"{"change":"operator","path":"/","old":"sum","new":"product"}]"#
+            )
+        );
+        assert_eq!(
+            changes[4].to_json_with(&PUZZLE_OPERATORS),
+            r#"{"change":"literal","path":"/0","old":"1","new":"6"}"#
+        );
+
+        let changes = diff(&decode("D2FE28"), &decode("C200B40A82"));
+        assert_eq!(changes[0].to_string(), "! / 2021 -> sum(1, 2)");
+        assert_eq!(
+            changes_to_json(&changes),
+            r#"[{"change":"replaced","path":"/","old":"2021","new":"sum(1, 2)"}]"#
+        );
+    }
+
+    #[test]
+    fn diff_inserted_and_removed() {
+        let old = operator(0, vec![literal(1), literal(2), literal(3), literal(4)]);
+        let new = operator(
+            0,
+            vec![literal(2), literal(5), literal(3), literal(6), literal(7)],
+        );
+        let text: Vec<String> = diff(&old, &new).iter().map(|c| c.to_string()).collect();
+        assert_eq!(text, ["- /0 1", "+ /1 5", "~ /3 literal 4 -> 6", "+ /4 7"]);
+
+        let changes = diff(&new, &old);
+        assert_eq!(
+            changes_to_json(&changes[..2]),
+            concat!(
+                r#"[{"change":"inserted","path":"/0","old":null,"new":"1"},"#,
+                r#"{"change":"removed","path":"/1","old":"5","new":null}]"#
+            )
+        );
+    }
+
+    // Path, value and bit span of every match
+    fn query(hex: &str, query: &str) -> Vec<(String, Option<u128>, (usize, usize))> {
+        let mut spans = SpanTrace::default();
+        let packet = BitStream::from_hex_str(hex)
+            .unwrap()
+            .read_packet_traced(&mut spans)
+            .unwrap();
+        let query: Query = query.parse().unwrap();
+        query
+            .select(&packet)
+            .iter()
+            .map(|m| {
+                let path = format_path(&m.path);
+                (path, m.packet.checked_eval(), spans.spans[m.index])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_paths_and_predicates() {
+        // equal_to(sum(1, 3), product(2, 2))
+        let hex = "9C0141080250320F1802104A08";
+        let all = [
+            ("/".to_string(), Some(1), (0, 102)),
+            ("/0".to_string(), Some(4), (22, 62)),
+            ("/0/0".to_string(), Some(1), (40, 51)),
+            ("/0/1".to_string(), Some(3), (51, 62)),
+            ("/1".to_string(), Some(4), (62, 102)),
+            ("/1/0".to_string(), Some(2), (80, 91)),
+            ("/1/1".to_string(), Some(2), (91, 102)),
+        ];
+
+        assert_eq!(query(hex, "//"), all);
+        assert_eq!(query(hex, "//*"), all);
+        assert_eq!(query(hex, "/"), all[..1]);
+        assert_eq!(query(hex, "/1/0"), all[5..6]);
+        assert_eq!(query(hex, "//literal[value>2]"), all[3..4]);
+        assert_eq!(query(hex, "//op[type=product]"), all[4..5]);
+        assert_eq!(query(hex, "//op[type=7][version<5]"), all[..1]);
+        assert_eq!(query(hex, "/*/*[type=literal][version>=2]").len(), 3);
+        assert_eq!(query(hex, "//[value=4]"), [all[1].clone(), all[4].clone()]);
+        assert_eq!(query(hex, "/2"), []);
+    }
+
+    #[test]
+    fn query_errors() {
+        for (query, error) in [
+            ("literal", "query \"literal\" must start with '/'"),
+            ("/x", "unknown packet test \"x\""),
+            ("//op[type=xor]", "unknown packet type \"xor\""),
+            ("//literal[value>abc]", "\"abc\" is not a number"),
+            ("//literal[size>1]", "unknown key \"size\""),
+            (
+                "//literal[value~1]",
+                "predicate \"value~1\" has no comparison",
+            ),
+            ("//literal[value=>1]", "unknown comparison \"=>\""),
+            ("//literal[value>1", "unclosed '['"),
+        ] {
+            assert_eq!(query.parse::<Query>(), Err(error.to_string()), "{}", query);
+        }
+
+        // Names resolve against the operator table the query is parsed with
+        let operators = PUZZLE_OPERATORS.with(5, XOR);
+        assert!(Query::parse_with("//op[type=xor]", &operators).is_ok());
+    }
+
+    fn hex_to_bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn to_base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut result = String::new();
+        for chunk in bytes.chunks(3) {
+            let group = chunk
+                .iter()
+                .chain([0, 0].iter())
+                .take(3)
+                .fold(0usize, |group, b| group << 8 | *b as usize);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    result.push(ALPHABET[group >> (18 - i * 6) & 63] as char);
+                } else {
+                    result.push('=');
+                }
+            }
+        }
+        result
+    }
+
+    // Packet and padding, which must not depend on how the transmission was spelled
+    fn decode_all(mut stream: BitStream) -> (Packet, Vec<Bit>) {
+        let packet = stream.read_packet().unwrap();
+        (packet, stream.remaining())
+    }
+
+    #[test]
+    fn bytes_and_base64_match_hex() {
+        for (hex, _, _) in EXAMPLES {
+            let expected = decode_all(BitStream::from_hex_str(hex).unwrap());
+            let bytes = hex_to_bytes(hex);
+            assert_eq!(
+                decode_all(BitStream::from_bytes(&bytes)),
+                expected,
+                "{}",
+                hex
+            );
+            #[cfg(feature = "std")]
+            assert_eq!(
+                decode_all(BitStream::from_reader(&bytes[..]).unwrap()),
+                expected,
+                "{}",
+                hex
+            );
+
+            let padded = to_base64(&bytes);
+            let unpadded = padded.trim_end_matches('=');
+            let wrapped: String = padded
+                .chars()
+                .enumerate()
+                .flat_map(|(i, c)| if i % 4 == 3 { vec![c, '\n'] } else { vec![c] })
+                .collect();
+            for base64 in [
+                padded.as_str(),
+                unpadded,
+                &wrapped,
+                &format!(" {}\r\n", padded),
+            ] {
+                let stream = BitStream::from_base64_str(base64).unwrap();
+                assert_eq!(decode_all(stream), expected, "{:?}", base64);
+            }
+        }
+        assert_eq!(to_base64(&hex_to_bytes("D2FE28")), "0v4o");
+        assert_eq!(to_base64(&hex_to_bytes("C200B40A82")), "wgC0CoI=");
+    }
+
+    #[test]
+    fn invalid_digits() {
+        for (result, position, digit) in [
+            (BitStream::from_hex_str("D2FE2G"), 5, 'G'),
+            (BitStream::from_hex_str("D2 FE28"), 2, ' '),
+            (BitStream::from_base64_str("0v4o?"), 4, '?'),
+            (BitStream::from_base64_str("0v-o"), 2, '-'),
+            (BitStream::from_base64_str("wgC0CoI=A"), 8, 'A'),
+            (BitStream::from_base64_str("wgC0\nCo=I="), 8, 'I'),
+        ] {
+            assert_eq!(
+                result.err(),
+                Some(DecodeError::InvalidDigit { position, digit })
+            );
+        }
+    }
+
+    #[test]
+    fn puzzle_examples() {
+        for (hex, version_sum, value) in EXAMPLES {
+            let packet = decode(hex);
+            assert_eq!(packet.version_sum(), version_sum, "{}", hex);
+            assert_eq!(packet.eval(), value, "{}", hex);
+            assert_reencodes(&packet, hex);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        for (hex, _, _) in EXAMPLES {
+            let packet = decode(hex);
+            let json = serde_json::to_string(&packet).unwrap();
+            let back: Packet = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, packet, "{}", json);
+            assert_reencodes(&back, hex);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_format() {
+        let json = serde_json::to_string(&decode("D2FE28")).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"literal","version":6,"type_id":4,"value":"2021"}"#
+        );
+
+        let json = serde_json::to_string(&decode("38006F45291200")).unwrap();
+        assert!(json.starts_with(
+            r#"{"kind":"operator","version":1,"type_id":6,"length_type":"total_bits","packets":["#
+        ));
+    }
 }
@@ -1,8 +1,12 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::ops::{Index, IndexMut};
 use std::str::FromStr;
-use std::{fmt, fs};
+use std::time::Instant;
+use std::{env, fmt, fs, thread};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Hash)]
 struct Image {
     rows: usize,
     cols: usize,
@@ -21,8 +25,11 @@ impl Image {
         }
     }
 
-    fn lit_count(&self) -> usize {
-        self.data.iter().filter(|a| **a == true).count()
+    fn lit_count(&self) -> LitCount {
+        if self.default {
+            return LitCount::Infinite;
+        }
+        LitCount::Finite(self.data.iter().filter(|a| **a).count())
     }
 }
 
@@ -35,7 +42,7 @@ impl Index<(i32, i32)> for Image {
             return &self.default;
         }
 
-        if (index.0 as usize >= self.rows || index.1 as usize >= self.cols) {
+        if index.0 as usize >= self.rows || index.1 as usize >= self.cols {
             return &self.default;
         }
 
@@ -50,7 +57,7 @@ impl IndexMut<(i32, i32)> for Image {
             panic!()
         }
 
-        if (index.0 as usize >= self.rows || index.1 as usize >= self.cols) {
+        if index.0 as usize >= self.rows || index.1 as usize >= self.cols {
             panic!()
         }
 
@@ -94,96 +101,2697 @@ impl fmt::Display for Image {
                     write!(f, ".")?
                 }
             }
-            writeln!(f, "")?
+            writeln!(f)?
         }
         Ok(())
     }
 }
 
-struct ImageEnhancer {
-    algorithm: Vec<bool>,
-}
+// Bitmap import/export for Image: lit pixels are white, dark pixels black
+impl Image {
+    // Copy of the image with `margin` pixels of the background colour on every side
+    fn with_margin(&self, margin: usize) -> Image {
+        let m = margin as i32;
+        let mut result = Image::new(self.rows + margin * 2, self.cols + margin * 2, self.default);
+        for r in 0..result.rows as i32 {
+            for c in 0..result.cols as i32 {
+                result[(r, c)] = self[(r - m, c - m)];
+            }
+        }
+        result
+    }
 
-impl ImageEnhancer {
-    fn enhance(&self, image: &Image, times: usize) -> Image {
-        let mut image = image.clone();
-        for _ in 0..times {
-            image = self.enhance_iteration(image);
+    // Binary PBM (P4), where a set bit is black
+    fn to_pbm(&self) -> Vec<u8> {
+        let mut result = format!("P4\n{} {}\n", self.cols, self.rows).into_bytes();
+        result.extend(self.packed_rows(false));
+        result
+    }
+
+    // Binary PGM (P5) with two grey levels
+    fn to_pgm(&self) -> Vec<u8> {
+        let mut result = format!("P5\n{} {}\n255\n", self.cols, self.rows).into_bytes();
+        result.extend(self.data.iter().map(|lit| if *lit { 255 } else { 0 }));
+        result
+    }
+
+    // 1-bit greyscale PNG, compressed with stored (uncompressed) deflate blocks
+    fn to_png(&self) -> Vec<u8> {
+        let row_len = self.cols.div_ceil(8);
+        let packed = self.packed_rows(true);
+        let mut raw = Vec::with_capacity(self.rows * (row_len + 1));
+        for r in 0..self.rows {
+            // Filter type 0 (none)
+            raw.push(0);
+            raw.extend(&packed[r * row_len..(r + 1) * row_len]);
         }
 
-        image
+        let mut header = Vec::new();
+        header.extend((self.cols as u32).to_be_bytes());
+        header.extend((self.rows as u32).to_be_bytes());
+        // Bit depth 1, greyscale, deflate, adaptive filtering, not interlaced
+        header.extend([1, 0, 0, 0, 0]);
+
+        let mut result = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut result, b"IHDR", &header);
+        png_chunk(&mut result, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut result, b"IEND", &[]);
+        result
     }
 
-    fn enhance_iteration(&self, image: Image) -> Image {
-        let s = 1 as i32;
-        let default = if image.default {
-            *self.algorithm.last().unwrap()
+    // Rows of 8 pixels per byte, most significant bit first, each row padded to a whole byte
+    fn packed_rows(&self, lit_bit: bool) -> Vec<u8> {
+        let row_len = self.cols.div_ceil(8);
+        let mut result = vec![0u8; self.rows * row_len];
+        for r in 0..self.rows {
+            for c in 0..self.cols {
+                if self.data[r * self.cols + c] == lit_bit {
+                    result[r * row_len + c / 8] |= 0x80 >> (c % 8);
+                }
+            }
+        }
+        result
+    }
+
+    // PNG or any of the PBM/PGM variants, told apart by their magic bytes
+    fn from_bitmap(data: &[u8]) -> Result<Image, ()> {
+        if data.starts_with(b"\x89PNG") {
+            Image::from_png(data)
         } else {
-            *self.algorithm.first().unwrap()
+            Image::from_pnm(data)
+        }
+    }
+
+    // P1/P4 bitmaps (set bit is black) and P2/P5 greymaps (brighter than half is lit)
+    fn from_pnm(data: &[u8]) -> Result<Image, ()> {
+        let kind = match data {
+            [b'P', kind @ b'1'..=b'5', ..] if *kind != b'3' => *kind,
+            _ => return Err(()),
         };
+        let bitmap = kind == b'1' || kind == b'4';
 
-        let mut result = Image::new(
-            image.rows + s as usize * 2,
-            image.cols + s as usize * 2,
-            default,
-        );
+        let mut pos = 2;
+        let mut fields = Vec::new();
+        while fields.len() < if bitmap { 2 } else { 3 } {
+            fields.push(pnm_number(data, &mut pos)?);
+        }
+        let (cols, rows) = (fields[0], fields[1]);
+        let max = if bitmap { 1 } else { fields[2] };
+        if max == 0 || max > 65535 {
+            return Err(());
+        }
 
-        for r in -s..(image.rows as i32 + s) {
-            for c in -s..(image.rows as i32 + s) {
-                let mut index = 0;
-                for r1 in -1..=1 {
-                    for c1 in -1..=1 {
-                        index <<= 1;
-                        if image[(r + r1, c + c1)] {
-                            index += 1;
-                        }
+        // Every pixel takes at least a bit of the file, so a forged header cannot allocate huge images
+        let pixels = rows.checked_mul(cols).ok_or(())?;
+        if pixels / 8 > data.len() {
+            return Err(());
+        }
+
+        let mut image = Image::new(rows, cols, false);
+        match kind {
+            b'1' => {
+                for pixel in image.data.iter_mut() {
+                    while data.get(pos).is_some_and(|b| b.is_ascii_whitespace()) {
+                        pos += 1;
+                    }
+                    *pixel = match data.get(pos) {
+                        Some(b'0') => true,
+                        Some(b'1') => false,
+                        _ => return Err(()),
+                    };
+                    pos += 1;
+                }
+            }
+            b'2' => {
+                for pixel in image.data.iter_mut() {
+                    *pixel = pnm_number(data, &mut pos)? * 2 > max;
+                }
+            }
+            b'4' => {
+                // Exactly one whitespace byte separates the header from the raster
+                let raster = data.get(pos + 1..).ok_or(())?;
+                let row_len = cols.div_ceil(8);
+                if raster.len() < rows.checked_mul(row_len).ok_or(())? {
+                    return Err(());
+                }
+                for r in 0..rows {
+                    for c in 0..cols {
+                        let byte = raster[r * row_len + c / 8];
+                        image.data[r * cols + c] = byte & 0x80 >> (c % 8) == 0;
+                    }
+                }
+            }
+            _ => {
+                let raster = data.get(pos + 1..).ok_or(())?;
+                let width = if max > 255 { 2 } else { 1 };
+                if raster.len() < pixels.checked_mul(width).ok_or(())? {
+                    return Err(());
+                }
+                for (pixel, sample) in image.data.iter_mut().zip(raster.chunks(width)) {
+                    let value = sample.iter().fold(0, |value, b| value << 8 | *b as usize);
+                    *pixel = value * 2 > max;
+                }
+            }
+        }
+
+        Ok(image)
+    }
+
+    // Any non-interlaced PNG; colour pixels are lit when their average channel is brighter than half
+    fn from_png(data: &[u8]) -> Result<Image, ()> {
+        if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Err(());
+        }
+
+        let mut header = None;
+        let mut palette: &[u8] = &[];
+        let mut compressed = Vec::new();
+        let mut pos = 8;
+        loop {
+            let len = u32::from_be_bytes(data.get(pos..pos + 4).ok_or(())?.try_into().unwrap());
+            let chunk = data.get(pos + 4..pos + 8 + len as usize).ok_or(())?;
+            let crc = data
+                .get(pos + 8 + len as usize..pos + 12 + len as usize)
+                .ok_or(())?;
+            if crc32(chunk).to_be_bytes() != crc {
+                return Err(());
+            }
+            let (kind, body) = chunk.split_at(4);
+            match kind {
+                b"IHDR" => header = Some(body),
+                b"PLTE" => palette = body,
+                b"IDAT" => compressed.extend(body),
+                b"IEND" => break,
+                _ => {}
+            }
+            pos += 12 + len as usize;
+        }
+
+        let header = header.filter(|h| h.len() == 13).ok_or(())?;
+        let cols = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let rows = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+        let (depth, colour_type) = (header[8] as usize, header[9]);
+        if header[10..13] != [0, 0, 0] {
+            return Err(());
+        }
+
+        let channels = match (colour_type, depth) {
+            (0, 1 | 2 | 4 | 8 | 16) | (3, 1 | 2 | 4 | 8) => 1,
+            (4, 8 | 16) => 2,
+            (2, 8 | 16) => 3,
+            (6, 8 | 16) => 4,
+            _ => return Err(()),
+        };
+
+        let row_bits = cols.checked_mul(channels * depth).ok_or(())?;
+        if rows.checked_mul(cols).is_none() {
+            return Err(());
+        }
+        let raw = zlib_inflate(&compressed)?;
+        let pixels = png_unfilter(&raw, rows, row_bits, (channels * depth).div_ceil(8))?;
+        let row_len = row_bits.div_ceil(8);
+        let max = (1 << depth) - 1;
+
+        // Sample `i` of a row, scaled to `max`
+        let sample = |row: &[u8], i: usize| -> usize {
+            match depth {
+                16 => (row[i * 2] as usize) << 8 | row[i * 2 + 1] as usize,
+                8 => row[i] as usize,
+                _ => (row[i * depth / 8] as usize >> (8 - depth - i * depth % 8)) & max,
+            }
+        };
+
+        let mut image = Image::new(rows, cols, false);
+        for r in 0..rows {
+            let row = &pixels[r * row_len..(r + 1) * row_len];
+            for c in 0..cols {
+                image.data[r * cols + c] = match colour_type {
+                    0 | 4 => sample(row, c * channels) * 2 > max,
+                    3 => {
+                        let entry = palette.get(sample(row, c) * 3..).ok_or(())?;
+                        let sum: usize = entry.iter().take(3).map(|b| *b as usize).sum();
+                        sum * 2 > 255 * 3
+                    }
+                    _ => (0..3).map(|i| sample(row, c * channels + i)).sum::<usize>() * 2 > max * 3,
+                };
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+// Next decimal number in a PBM/PGM header or plain raster, skipping whitespace and comments
+fn pnm_number(data: &[u8], pos: &mut usize) -> Result<usize, ()> {
+    loop {
+        match data.get(*pos) {
+            Some(b'#') => {
+                while data.get(*pos).is_some_and(|b| *b != b'\n') {
+                    *pos += 1;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+
+    let start = *pos;
+    while data.get(*pos).is_some_and(|b| b.is_ascii_digit()) {
+        *pos += 1;
+    }
+    std::str::from_utf8(&data[start..*pos])
+        .unwrap()
+        .parse()
+        .map_err(|_| ())
+}
+
+// Length, type, data, CRC of type and data
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend(kind);
+    out.extend(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+// Undo the per-row PNG filters; `row_bits` excludes the filter byte, `bpp` is bytes per pixel (at least 1)
+fn png_unfilter(raw: &[u8], rows: usize, row_bits: usize, bpp: usize) -> Result<Vec<u8>, ()> {
+    let row_len = row_bits.div_ceil(8);
+    if raw.len() < rows.checked_mul(row_len + 1).ok_or(())? {
+        return Err(());
+    }
+
+    let mut result = vec![0u8; rows * row_len];
+    for r in 0..rows {
+        let filter = raw[r * (row_len + 1)];
+        let line = &raw[r * (row_len + 1) + 1..(r + 1) * (row_len + 1)];
+        for i in 0..row_len {
+            let a = if i >= bpp {
+                result[r * row_len + i - bpp]
+            } else {
+                0
+            };
+            let b = if r > 0 {
+                result[(r - 1) * row_len + i]
+            } else {
+                0
+            };
+            let c = if r > 0 && i >= bpp {
+                result[(r - 1) * row_len + i - bpp]
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => {
+                    let p = a as i16 + b as i16 - c as i16;
+                    let (pa, pb, pc) = (
+                        (p - a as i16).abs(),
+                        (p - b as i16).abs(),
+                        (p - c as i16).abs(),
+                    );
+                    if pa <= pb && pa <= pc {
+                        a
+                    } else if pb <= pc {
+                        b
+                    } else {
+                        c
                     }
                 }
+                _ => return Err(()),
+            };
+            result[r * row_len + i] = line[i].wrapping_add(predicted);
+        }
+    }
+
+    Ok(result)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// zlib stream made only of stored blocks, which every decoder can read
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut result = vec![0x78, 0x01];
+    let mut blocks = data.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        result.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        result.push(blocks.peek().is_none() as u8);
+        result.extend((block.len() as u16).to_le_bytes());
+        result.extend((!(block.len() as u16)).to_le_bytes());
+        result.extend(block);
+    }
+    result.extend(adler32(data).to_be_bytes());
+    result
+}
+
+// Least significant bit first, as DEFLATE packs everything except Huffman codes
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, n: usize) -> Result<usize, ()> {
+        let mut result = 0;
+        for i in 0..n {
+            let byte = *self.data.get(self.bit / 8).ok_or(())?;
+            result |= ((byte >> (self.bit % 8)) as usize & 1) << i;
+            self.bit += 1;
+        }
+        Ok(result)
+    }
+}
+
+// Canonical Huffman code: how many codes of each length, and the symbols in code order
+struct Huffman {
+    counts: [usize; 16],
+    symbols: Vec<usize>,
+}
+
+impl Huffman {
+    fn new(lengths: &[usize]) -> Huffman {
+        let mut counts = [0; 16];
+        for len in lengths {
+            counts[*len] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols = Vec::new();
+        for len in 1..16 {
+            symbols.extend((0..lengths.len()).filter(|s| lengths[*s] == len));
+        }
+        Huffman { counts, symbols }
+    }
+
+    // Huffman codes are packed most significant bit first, one bit at a time
+    fn decode(&self, bits: &mut BitReader) -> Result<usize, ()> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..16 {
+            code |= bits.read(1)?;
+            let count = self.counts[len];
+            if code < first + count {
+                return Ok(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(())
+    }
+}
+
+// zlib/DEFLATE decoder (RFC 1950/1951) for reading PNG image data
+fn zlib_inflate(data: &[u8]) -> Result<Vec<u8>, ()> {
+    const LENGTH_BASE: [usize; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LENGTH_EXTRA: [usize; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DISTANCE_BASE: [usize; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+    const DISTANCE_EXTRA: [usize; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+        13, 13,
+    ];
+    // Order in which code length code lengths are stored
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+
+    if data.len() < 6 || data[0] & 0x0f != 8 || data[1] & 0x20 != 0 {
+        return Err(());
+    }
+    if !(data[0] as usize * 256 + data[1] as usize).is_multiple_of(31) {
+        return Err(());
+    }
+
+    let mut bits = BitReader {
+        data: &data[2..],
+        bit: 0,
+    };
+    let mut out: Vec<u8> = Vec::new();
+    loop {
+        let last = bits.read(1)? == 1;
+        match bits.read(2)? {
+            0 => {
+                let start = bits.bit.div_ceil(8);
+                let header = bits.data.get(start..start + 4).ok_or(())?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(());
+                }
+                let block = bits
+                    .data
+                    .get(start + 4..start + 4 + len as usize)
+                    .ok_or(())?;
+                out.extend(block);
+                bits.bit = (start + 4 + len as usize) * 8;
+            }
+            kind @ (1 | 2) => {
+                let (lengths, distances) = if kind == 1 {
+                    let mut lengths = [8; 288];
+                    lengths[144..256].fill(9);
+                    lengths[256..280].fill(7);
+                    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+                } else {
+                    let literals = bits.read(5)? + 257;
+                    let distances = bits.read(5)? + 1;
+                    let mut code_lengths = [0; 19];
+                    for i in 0..bits.read(4)? + 4 {
+                        code_lengths[ORDER[i]] = bits.read(3)?;
+                    }
+                    let code_lengths = Huffman::new(&code_lengths);
 
-                assert!(index < 512);
+                    let mut lengths = Vec::new();
+                    while lengths.len() < literals + distances {
+                        let (value, repeat) = match code_lengths.decode(&mut bits)? {
+                            16 => (*lengths.last().ok_or(())?, 3 + bits.read(2)?),
+                            17 => (0, 3 + bits.read(3)?),
+                            18 => (0, 11 + bits.read(7)?),
+                            len => (len, 1),
+                        };
+                        lengths.extend((0..repeat).map(|_| value));
+                    }
+                    if lengths.len() != literals + distances {
+                        return Err(());
+                    }
+                    (
+                        Huffman::new(&lengths[..literals]),
+                        Huffman::new(&lengths[literals..]),
+                    )
+                };
 
-                result[(r + s, c + s)] = self.algorithm[index];
+                loop {
+                    let symbol = lengths.decode(&mut bits)?;
+                    if symbol < 256 {
+                        out.push(symbol as u8);
+                    } else if symbol == 256 {
+                        break;
+                    } else {
+                        let i = symbol - 257;
+                        let len = *LENGTH_BASE.get(i).ok_or(())? + bits.read(LENGTH_EXTRA[i])?;
+                        let d = distances.decode(&mut bits)?;
+                        let distance =
+                            *DISTANCE_BASE.get(d).ok_or(())? + bits.read(DISTANCE_EXTRA[d])?;
+                        if distance > out.len() {
+                            return Err(());
+                        }
+                        let start = out.len() - distance;
+                        for i in 0..len {
+                            out.push(out[start + i]);
+                        }
+                    }
+                }
             }
+            _ => return Err(()),
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    let end = bits.bit.div_ceil(8);
+    let checksum = bits.data.get(end..end + 4).ok_or(())?;
+    if adler32(&out).to_be_bytes() != checksum {
+        return Err(());
+    }
+    Ok(out)
+}
+
+// Every pixel outside the tracked area matches the background, so a lit background means infinitely many
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LitCount {
+    Finite(usize),
+    Infinite,
+}
+
+// "ToString" functionality for LitCount
+impl fmt::Display for LitCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LitCount::Finite(n) => write!(f, "{}", n),
+            LitCount::Infinite => write!(f, "infinite"),
+        }
+    }
+}
+
+// Sparse alternative to Image: only the pixels that differ from `default` are stored.
+// It tracks the same area as the equivalent Image, and indexes relative to its top-left corner
+#[derive(Clone, Debug, PartialEq)]
+struct SparseImage {
+    top: i32,
+    left: i32,
+    rows: usize,
+    cols: usize,
+    cells: HashSet<(i32, i32)>,
+    default: bool,
+}
+
+impl SparseImage {
+    // Absolute coordinates, unlike `Index`
+    fn pixel(&self, r: i32, c: i32) -> bool {
+        self.cells.contains(&(r, c)) != self.default
+    }
+
+    fn lit_count(&self) -> LitCount {
+        if self.default {
+            return LitCount::Infinite;
         }
+        LitCount::Finite(self.cells.len())
+    }
 
+    fn to_dense(&self) -> Image {
+        let mut result = Image::new(self.rows, self.cols, self.default);
+        for r in 0..self.rows as i32 {
+            for c in 0..self.cols as i32 {
+                result[(r, c)] = self[(r, c)];
+            }
+        }
         result
     }
 }
 
-// "Parse" functionality for Image
-impl FromStr for ImageEnhancer {
+impl From<&Image> for SparseImage {
+    fn from(image: &Image) -> Self {
+        let mut cells = HashSet::new();
+        for r in 0..image.rows as i32 {
+            for c in 0..image.cols as i32 {
+                if image[(r, c)] != image.default {
+                    cells.insert((r, c));
+                }
+            }
+        }
+
+        SparseImage {
+            top: 0,
+            left: 0,
+            rows: image.rows,
+            cols: image.cols,
+            cells,
+            default: image.default,
+        }
+    }
+}
+
+// 2-d index for SparseImage
+impl Index<(i32, i32)> for SparseImage {
+    type Output = bool;
+
+    fn index(&self, index: (i32, i32)) -> &Self::Output {
+        if self.pixel(self.top + index.0, self.left + index.1) {
+            &true
+        } else {
+            &false
+        }
+    }
+}
+
+// "Parse" functionality for SparseImage
+impl FromStr for SparseImage {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let result = ImageEnhancer {
-            algorithm: s.trim().chars().map(|c| c == '#').collect(),
-        };
+        Ok(SparseImage::from(&s.parse::<Image>()?))
+    }
+}
 
-        Ok(result)
+// "ToString" functionality for SparseImage
+impl fmt::Display for SparseImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_dense().fmt(f)
     }
 }
 
-fn main() {
-    let input = fs::read_to_string("input-day-20.txt").unwrap();
-    let mut iter = input.split("\n");
+// Dense alternative to Image with 64 pixels per word, every row padded to whole words
+#[derive(Clone, Debug, PartialEq)]
+struct PackedImage {
+    rows: usize,
+    cols: usize,
+    words_per_row: usize,
+    data: Vec<u64>,
+    default: bool,
+}
 
-    let a: Vec<&str> = iter.by_ref().take_while(|a| !a.is_empty()).collect();
-    let b: Vec<&str> = iter.collect();
+impl PackedImage {
+    fn new(rows: usize, cols: usize, default: bool) -> PackedImage {
+        let words_per_row = cols.div_ceil(64);
+        PackedImage {
+            rows,
+            cols,
+            words_per_row,
+            data: vec![0; rows * words_per_row],
+            default,
+        }
+    }
 
-    let algorithm = a.join("");
-    let image_data = b.join("\n");
+    fn get(&self, r: i32, c: i32) -> bool {
+        if r < 0 || c < 0 || r as usize >= self.rows || c as usize >= self.cols {
+            return self.default;
+        }
 
-    let image: Image = image_data.parse().unwrap();
-    let enhancer: ImageEnhancer = algorithm.parse().unwrap();
+        let (r, c) = (r as usize, c as usize);
+        self.data[r * self.words_per_row + c / 64] >> (c % 64) & 1 == 1
+    }
 
-    println!("{:?}", algorithm);
-    println!("{}", image);
-    println!("{}", enhancer.enhance(&image, 2).lit_count());
-    println!("{}", enhancer.enhance(&image, 50).lit_count());
+    fn set(&mut self, r: usize, c: usize) {
+        self.data[r * self.words_per_row + c / 64] |= 1 << (c % 64);
+    }
 
-    // println!("{}", enhancer.enhance(&image, 0));
-    // println!("{}", enhancer.enhance(&image, 1));
-    // println!("{}", enhancer.enhance(&image, 2));
-    // println!("{}", enhancer.enhance(&image, 3));
-    // println!("{}", enhancer.enhance(&image, 4));
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn lit_count(&self) -> LitCount {
+        if self.default {
+            return LitCount::Infinite;
+        }
+        LitCount::Finite(self.data.iter().map(|w| w.count_ones() as usize).sum())
+    }
+
+    fn to_dense(&self) -> Image {
+        let mut result = Image::new(self.rows, self.cols, self.default);
+        for r in 0..self.rows as i32 {
+            for c in 0..self.cols as i32 {
+                result[(r, c)] = self.get(r, c);
+            }
+        }
+        result
+    }
+}
+
+impl From<&Image> for PackedImage {
+    fn from(image: &Image) -> Self {
+        let mut result = PackedImage::new(image.rows, image.cols, image.default);
+        for r in 0..image.rows {
+            for c in 0..image.cols {
+                if image[(r as i32, c as i32)] {
+                    result.set(r, c);
+                }
+            }
+        }
+        result
+    }
+}
+
+// 2-d index for PackedImage
+impl Index<(i32, i32)> for PackedImage {
+    type Output = bool;
+
+    fn index(&self, index: (i32, i32)) -> &Self::Output {
+        if self.get(index.0, index.1) {
+            &true
+        } else {
+            &false
+        }
+    }
+}
+
+// "Parse" functionality for PackedImage
+impl FromStr for PackedImage {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(PackedImage::from(&s.parse::<Image>()?))
+    }
+}
+
+// "ToString" functionality for PackedImage
+impl fmt::Display for PackedImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_dense().fmt(f)
+    }
+}
+
+// Image cut down after every step to the smallest box holding the pixels that differ from the
+// background, plus `margin`. `top`/`left` place its corner relative to the starting image
+#[derive(Clone, Debug, PartialEq)]
+struct TrimmedImage {
+    image: Image,
+    top: i32,
+    left: i32,
+    margin: usize,
+}
+
+impl TrimmedImage {
+    fn new(image: &Image, margin: usize) -> TrimmedImage {
+        let mut result = TrimmedImage {
+            image: image.clone(),
+            top: 0,
+            left: 0,
+            margin,
+        };
+        result.trim();
+        result
+    }
+
+    fn trim(&mut self) {
+        let image = &self.image;
+        let (mut top, mut left, mut bottom, mut right) = (image.rows, image.cols, 0, 0);
+        for r in 0..image.rows {
+            for c in 0..image.cols {
+                if image.data[r * image.cols + c] != image.default {
+                    top = top.min(r);
+                    left = left.min(c);
+                    bottom = bottom.max(r + 1);
+                    right = right.max(c + 1);
+                }
+            }
+        }
+        // Nothing but background
+        if top > bottom {
+            (top, left, bottom, right) = (0, 0, 0, 0);
+        }
+
+        let m = self.margin;
+        let (rows, cols) = (bottom - top + m * 2, right - left + m * 2);
+        let (top, left) = (top as i32 - m as i32, left as i32 - m as i32);
+        if (top, left, rows, cols) == (0, 0, image.rows, image.cols) {
+            return;
+        }
+
+        let mut result = Image::new(rows, cols, image.default);
+        for r in 0..rows as i32 {
+            for c in 0..cols as i32 {
+                result[(r, c)] = image[(top + r, left + c)];
+            }
+        }
+        self.image = result;
+        self.top += top;
+        self.left += left;
+    }
+
+    // Absolute coordinates
+    fn pixel(&self, r: i32, c: i32) -> bool {
+        self.image[(r - self.top, c - self.left)]
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn lit_count(&self) -> LitCount {
+        self.image.lit_count()
+    }
+
+    // Any area in absolute coordinates, e.g. the one an untrimmed run covers
+    fn crop(&self, top: i32, left: i32, rows: usize, cols: usize) -> Image {
+        let mut result = Image::new(rows, cols, self.image.default);
+        for r in 0..rows as i32 {
+            for c in 0..cols as i32 {
+                result[(r, c)] = self.pixel(top + r, left + c);
+            }
+        }
+        result
+    }
+}
+
+// Image representations ImageEnhancer can step
+trait Plane: Clone {
+    fn enhanced(self, enhancer: &ImageEnhancer) -> Self;
+}
+
+impl Plane for Image {
+    fn enhanced(self, enhancer: &ImageEnhancer) -> Self {
+        enhancer.enhance_iteration(self)
+    }
+}
+
+impl Plane for PackedImage {
+    fn enhanced(self, enhancer: &ImageEnhancer) -> Self {
+        enhancer.enhance_packed_iteration(self)
+    }
+}
+
+impl Plane for TrimmedImage {
+    fn enhanced(self, enhancer: &ImageEnhancer) -> Self {
+        let mut result = TrimmedImage {
+            image: enhancer.enhance_iteration(self.image),
+            top: self.top - enhancer.kernel.radius(),
+            left: self.left - enhancer.kernel.radius(),
+            margin: self.margin,
+        };
+        result.trim();
+        result
+    }
+}
+
+impl Plane for SparseImage {
+    fn enhanced(self, enhancer: &ImageEnhancer) -> Self {
+        enhancer.enhance_sparse_iteration(self)
+    }
+}
+
+// Which pixels around an output pixel go into the table index, the first offset being the most
+// significant bit. The puzzle's 3x3 window read row by row is the default
+#[derive(Clone, Debug, PartialEq)]
+struct Kernel {
+    offsets: Vec<(i32, i32)>,
+    // Index by the centre pixel and how many of the other pixels are lit instead of the exact
+    // window: 2 * n entries instead of 2^n, e.g. 50 rather than 33554432 for 5x5
+    counting: bool,
+}
+
+impl Kernel {
+    // Every pixel within `radius` in both directions, row by row
+    fn square(radius: i32) -> Kernel {
+        let offsets = (-radius..=radius)
+            .flat_map(|r| (-radius..=radius).map(move |c| (r, c)))
+            .collect();
+        Kernel {
+            offsets,
+            counting: false,
+        }
+    }
+
+    // The centre and its four edge neighbours, 32 entries
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn von_neumann() -> Kernel {
+        Kernel {
+            offsets: vec![(-1, 0), (0, -1), (0, 0), (0, 1), (1, 0)],
+            counting: false,
+        }
+    }
+
+    // Hexagonal grid in axial coordinates: rows are sheared so that (-1, -1) and (1, 1) are
+    // neighbours and (-1, 1) and (1, -1) are not. 128 entries
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn hexagonal() -> Kernel {
+        Kernel {
+            offsets: vec![(-1, -1), (-1, 0), (0, -1), (0, 0), (0, 1), (1, 0), (1, 1)],
+            counting: false,
+        }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn counting(self) -> Kernel {
+        Kernel {
+            counting: true,
+            ..self
+        }
+    }
+
+    // How far a pixel's influence spreads in one step
+    fn radius(&self) -> i32 {
+        self.offsets
+            .iter()
+            .map(|(r, c)| r.abs().max(c.abs()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // None once a full table could not even be indexed
+    fn table_len(&self) -> Option<usize> {
+        if self.counting {
+            Some(2 * self.others())
+        } else {
+            u32::try_from(self.offsets.len())
+                .ok()
+                .filter(|&n| n < usize::BITS)
+                .map(|n| 1 << n)
+        }
+    }
+
+    // Number of possible counts of lit pixels other than the centre
+    fn others(&self) -> usize {
+        self.offsets.iter().filter(|o| **o != (0, 0)).count() + 1
+    }
+
+    // `pixel` takes an offset; all-dark is always index 0, and all-lit the last index unless a
+    // counting kernel leaves out the centre
+    fn index(&self, pixel: impl Fn(i32, i32) -> bool) -> usize {
+        if self.counting {
+            let mut centre = 0;
+            let mut count = 0;
+            for (r, c) in &self.offsets {
+                if pixel(*r, *c) {
+                    if (*r, *c) == (0, 0) {
+                        centre = 1;
+                    } else {
+                        count += 1;
+                    }
+                }
+            }
+            centre * self.others() + count
+        } else {
+            let mut index = 0;
+            for (r, c) in &self.offsets {
+                index = index << 1 | pixel(*r, *c) as usize;
+            }
+            index
+        }
+    }
+}
+
+impl Default for Kernel {
+    fn default() -> Self {
+        Kernel::square(1)
+    }
+}
+
+// "Parse" functionality for Kernel: a '#'/'.' mask with odd sides, centred on the output pixel and
+// read row by row
+impl FromStr for Kernel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lines: Vec<&str> = s.split("\n").filter(|row| !row.trim().is_empty()).collect();
+        let (rows, cols) = (lines.len() as i32, lines.first().ok_or(())?.len() as i32);
+        if rows % 2 == 0 || cols % 2 == 0 || lines.iter().any(|l| l.len() as i32 != cols) {
+            return Err(());
+        }
+
+        let mut offsets = Vec::new();
+        for (r, line) in lines.iter().enumerate() {
+            for (c, cell) in line.chars().enumerate() {
+                match cell {
+                    '#' => offsets.push((r as i32 - rows / 2, c as i32 - cols / 2)),
+                    '.' => {}
+                    _ => return Err(()),
+                }
+            }
+        }
+
+        Ok(Kernel {
+            offsets,
+            counting: false,
+        })
+    }
+}
+
+struct ImageEnhancer {
+    // Indexed as `kernel` says, `kernel.table_len()` entries
+    algorithm: Vec<bool>,
+    kernel: Kernel,
+    // Results do not depend on it, 1 keeps everything on the calling thread
+    threads: usize,
+}
+
+impl ImageEnhancer {
+    fn with_kernel(kernel: Kernel, algorithm: Vec<bool>) -> Result<ImageEnhancer, ()> {
+        if Some(algorithm.len()) != kernel.table_len() {
+            return Err(());
+        }
+        Ok(ImageEnhancer {
+            algorithm,
+            kernel,
+            threads: 1,
+        })
+    }
+
+    // Birth and survival by number of lit pixels around the centre, for any kernel
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn counting(kernel: &Kernel, birth: &[usize], survival: &[usize]) -> ImageEnhancer {
+        let kernel = kernel.clone().counting();
+        let others = kernel.others();
+        let algorithm = (0..others * 2)
+            .map(|i| {
+                if i < others {
+                    birth.contains(&i)
+                } else {
+                    survival.contains(&(i - others))
+                }
+            })
+            .collect();
+        ImageEnhancer::with_kernel(kernel, algorithm).unwrap()
+    }
+
+    fn enhance<P: Plane>(&self, image: &P, times: usize) -> P {
+        let mut image = image.clone();
+        for _ in 0..times {
+            image = image.enhanced(self);
+        }
+
+        image
+    }
+
+    // How the infinite background evolves from dark, decided by the all-dark and all-lit entries
+    fn background(&self) -> Background {
+        match (self.algorithm[0], self.next_default(true)) {
+            (false, _) => Background::Dark,
+            (true, false) => Background::Blinking,
+            (true, true) => Background::Lit,
+        }
+    }
+
+    // What the infinitely many pixels outside the image turn into
+    fn next_default(&self, default: bool) -> bool {
+        self.algorithm[self.kernel.index(|_, _| default)]
+    }
+
+    fn enhance_iteration(&self, image: Image) -> Image {
+        let s = self.kernel.radius();
+        let default = self.next_default(image.default);
+        // Unrolled by the compiler, unlike a walk over `kernel.offsets`
+        let moore = self.kernel == Kernel::default();
+
+        let mut result = Image::new(
+            image.rows + s as usize * 2,
+            image.cols + s as usize * 2,
+            default,
+        );
+
+        let cols = result.cols;
+        self.for_each_row(&mut result.data, cols, |out_r, row| {
+            let r = out_r as i32 - s;
+            for c in -s..(image.cols as i32 + s) {
+                let index = if moore {
+                    let mut index = 0;
+                    for r1 in -1..=1 {
+                        for c1 in -1..=1 {
+                            index = index << 1 | image[(r + r1, c + c1)] as usize;
+                        }
+                    }
+                    index
+                } else {
+                    self.kernel.index(|r1, c1| image[(r + r1, c + c1)])
+                };
+                row[(c + s) as usize] = self.algorithm[index];
+            }
+        });
+
+        result
+    }
+
+    // Output rows only depend on the previous image, so bands of them can go to separate threads
+    fn for_each_row<T: Send>(
+        &self,
+        data: &mut [T],
+        row_len: usize,
+        f: impl Fn(usize, &mut [T]) + Sync,
+    ) {
+        // Zero-width images have rows but nothing in them
+        if row_len == 0 {
+            return;
+        }
+
+        let rows = data.len() / row_len;
+        let threads = self.threads.clamp(1, rows.max(1));
+
+        if threads == 1 {
+            for (r, row) in data.chunks_mut(row_len).enumerate() {
+                f(r, row);
+            }
+            return;
+        }
+
+        let band = rows.div_ceil(threads);
+        let f = &f;
+        thread::scope(|scope| {
+            for (i, chunk) in data.chunks_mut(band * row_len).enumerate() {
+                scope.spawn(move || {
+                    for (j, row) in chunk.chunks_mut(row_len).enumerate() {
+                        f(i * band + j, row);
+                    }
+                });
+            }
+        });
+    }
+
+    // Same result as `enhance`, two steps per pass where possible
+    fn enhance_composite(&self, table: &CompositeTable, image: &Image, times: usize) -> Image {
+        let mut image = image.clone();
+        for _ in 0..times / 2 {
+            image = self.enhance_composite_iteration(table, image);
+        }
+        if times % 2 == 1 {
+            image = self.enhance_iteration(image);
+        }
+
+        image
+    }
+
+    // One 5x5 lookup per output pixel, the window sliding along the row like the packed engine's.
+    // Reads come from a copy with a background border, so they need no bounds checks
+    fn enhance_composite_iteration(&self, table: &CompositeTable, image: Image) -> Image {
+        let default = table.get(if image.default {
+            CompositeTable::LEN - 1
+        } else {
+            0
+        });
+        let mut result = Image::new(image.rows + 4, image.cols + 4, default);
+        let padded = image.with_margin(4);
+
+        let cols = result.cols;
+        self.for_each_row(&mut result.data, cols, |out_r, row| {
+            let rows: Vec<&[bool]> = (out_r..out_r + 5)
+                .map(|r| &padded.data[r * padded.cols..(r + 1) * padded.cols])
+                .collect();
+            let column = |c: usize| rows.iter().fold(0, |bits, row| bits << 5 | row[c] as usize);
+
+            let mut index = 0;
+            for c in 0..4 {
+                index = (index << 1) & 0b11110_11110_11110_11110_11110 | column(c);
+            }
+            for (c, pixel) in row.iter_mut().enumerate() {
+                index = (index << 1) & 0b11110_11110_11110_11110_11110 | column(c + 4);
+                *pixel = table.get(index);
+            }
+        });
+
+        result
+    }
+
+    // The 9-bit index holds three bits per row; moving one pixel right drops the leftmost
+    // column and shifts in the next one, read straight from the row words
+    fn enhance_packed_iteration(&self, image: PackedImage) -> PackedImage {
+        assert_eq!(self.kernel, Kernel::default(), "packed engine is 3x3 only");
+        let mut result = PackedImage::new(
+            image.rows + 2,
+            image.cols + 2,
+            self.next_default(image.default),
+        );
+
+        let background_word = if image.default { !0u64 } else { 0 };
+        let background_column = if image.default { 0b001_001_001 } else { 0 };
+        let cols = image.cols as i32;
+        let row = |r: i32| {
+            if r < 0 || r as usize >= image.rows {
+                None
+            } else {
+                let start = r as usize * image.words_per_row;
+                Some(&image.data[start..start + image.words_per_row])
+            }
+        };
+
+        let words_per_row = result.words_per_row;
+        self.for_each_row(&mut result.data, words_per_row, |out_r, out| {
+            let r = out_r as i32 - 1;
+            let rows = [row(r - 1), row(r), row(r + 1)];
+            let mut words = [0u64; 3];
+
+            // Window for output column -1 covers input columns -2..=0, only column 0 may be set
+            let mut index = background_column << 1 | background_column;
+            let mut out_word = 0u64;
+
+            for c in -1..cols + 1 {
+                let next = c + 1;
+                let column = if next < cols {
+                    if next % 64 == 0 {
+                        for i in 0..3 {
+                            words[i] = match rows[i] {
+                                Some(row) => row[next as usize / 64],
+                                None => background_word,
+                            };
+                        }
+                    }
+
+                    let column =
+                        ((words[0] & 1) << 6 | (words[1] & 1) << 3 | words[2] & 1) as usize;
+                    for word in words.iter_mut() {
+                        *word >>= 1;
+                    }
+                    column
+                } else {
+                    background_column
+                };
+
+                index = (index << 1) & 0b110_110_110 | column;
+
+                let out_c = (c + 1) as usize;
+                out_word |= (self.algorithm[index] as u64) << (out_c % 64);
+                if out_c % 64 == 63 || c == cols {
+                    out[out_c / 64] = out_word;
+                    out_word = 0;
+                }
+            }
+        });
+
+        result
+    }
+
+    // Only pixels next to a stored cell can come out different from the new background
+    fn enhance_sparse_iteration(&self, image: SparseImage) -> SparseImage {
+        let default = self.next_default(image.default);
+
+        let mut candidates = HashSet::new();
+        for (r, c) in &image.cells {
+            for (r1, c1) in &self.kernel.offsets {
+                candidates.insert((r - r1, c - c1));
+            }
+        }
+
+        let mut cells = HashSet::new();
+        for (r, c) in candidates {
+            let index = self.kernel.index(|r1, c1| image.pixel(r + r1, c + c1));
+            if self.algorithm[index] != default {
+                cells.insert((r, c));
+            }
+        }
+
+        let s = self.kernel.radius();
+        SparseImage {
+            top: image.top - s,
+            left: image.left - s,
+            rows: image.rows + s as usize * 2,
+            cols: image.cols + s as usize * 2,
+            cells,
+            default,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Background {
+    // Stays dark forever
+    Dark,
+    // Lit after every odd number of steps, dark after every even number
+    Blinking,
+    // Lit from the first step on
+    Lit,
+}
+
+// Two 3x3 steps folded into one 5x5 lookup, 2^25 entries packed 64 to a word. The window is read
+// row by row with the top-left pixel as the high bit; the background flip is included, since an
+// all-dark or all-lit window gives the background two steps on
+struct CompositeTable {
+    words: Vec<u64>,
+}
+
+impl CompositeTable {
+    const LEN: usize = 1 << 25;
+
+    fn new(enhancer: &ImageEnhancer) -> CompositeTable {
+        assert_eq!(
+            enhancer.kernel,
+            Kernel::default(),
+            "composite tables are 3x3 only"
+        );
+        let algorithm = &enhancer.algorithm;
+
+        // Three 5-pixel rows give the three middle pixels of the row in between after one step
+        let middle: Vec<usize> = (0..1 << 15)
+            .map(|rows: usize| {
+                let (a, b, c) = (rows >> 10, rows >> 5 & 31, rows & 31);
+                (0..3).fold(0, |bits, j| {
+                    let shift = 2 - j;
+                    let index = (a >> shift & 7) << 6 | (b >> shift & 7) << 3 | c >> shift & 7;
+                    bits << 1 | algorithm[index] as usize
+                })
+            })
+            .collect();
+
+        let mut words = vec![0u64; CompositeTable::LEN / 64];
+        for top in 0..1 << 15 {
+            let first = middle[top];
+            for r3 in 0..32 {
+                let second = middle[(top & 0b11111_11111) << 5 | r3];
+                for r4 in 0..32 {
+                    let third = middle[(top & 31) << 10 | r3 << 5 | r4];
+                    let index = top << 10 | r3 << 5 | r4;
+                    if algorithm[first << 6 | second << 3 | third] {
+                        words[index / 64] |= 1 << (index % 64);
+                    }
+                }
+            }
+        }
+
+        CompositeTable { words }
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.words[index / 64] >> (index % 64) & 1 == 1
+    }
+}
+
+// "ToString" functionality for Background
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Dark => write!(f, "dark background"),
+            Background::Blinking => write!(f, "blinking background, odd steps are infinite"),
+            Background::Lit => write!(f, "lit background, every step is infinite"),
+        }
+    }
+}
+
+// "Parse" functionality for ImageEnhancer: exactly 512 '#'/'.' entries for the default kernel
+impl FromStr for ImageEnhancer {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.chars().any(|c| c != '#' && c != '.') {
+            return Err(());
+        }
+
+        ImageEnhancer::with_kernel(Kernel::default(), s.chars().map(|c| c == '#').collect())
+    }
+}
+
+// State after one iteration, for plotting. Changed pixels are infinite when the background flips
+#[derive(Clone, Debug, PartialEq)]
+struct Stats {
+    iteration: usize,
+    lit: LitCount,
+    background: bool,
+    // Pixels that differ from the background: (top, left, bottom, right), bottom and right
+    // exclusive, relative to the starting image. None when there are none
+    bounds: Option<(i32, i32, i32, i32)>,
+    changed: LitCount,
+}
+
+impl Stats {
+    const CSV_HEADER: &'static str = "iteration,lit,background,top,left,bottom,right,changed";
+
+    fn to_csv(&self) -> String {
+        let bounds = match self.bounds {
+            Some((top, left, bottom, right)) => format!("{},{},{},{}", top, left, bottom, right),
+            None => ",,,".to_string(),
+        };
+        format!(
+            "{},{},{},{},{}",
+            self.iteration, self.lit, self.background as u8, bounds, self.changed
+        )
+    }
+
+    fn to_json(&self) -> String {
+        let count = |count: LitCount| match count {
+            LitCount::Finite(n) => n.to_string(),
+            LitCount::Infinite => "\"infinite\"".to_string(),
+        };
+        let bounds = match self.bounds {
+            Some((top, left, bottom, right)) => format!(
+                "{{\"top\":{},\"left\":{},\"bottom\":{},\"right\":{}}}",
+                top, left, bottom, right
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"iteration\":{},\"lit\":{},\"background\":{},\"bounds\":{},\"changed\":{}}}",
+            self.iteration,
+            count(self.lit),
+            self.background,
+            bounds,
+            count(self.changed)
+        )
+    }
+}
+
+fn stats_to_json(stats: &[Stats]) -> String {
+    let stats: Vec<String> = stats.iter().map(|s| s.to_json()).collect();
+    format!("[{}]", stats.join(","))
+}
+
+impl ImageEnhancer {
+    // Same as `enhance` on a dense image, handing `emit` the stats of the starting image and of
+    // every iteration after it
+    fn enhance_with_stats(
+        &self,
+        image: &Image,
+        times: usize,
+        mut emit: impl FnMut(Stats),
+    ) -> Image {
+        let s = self.kernel.radius();
+        let stats = |iteration: usize, image: &Image, changed: LitCount| {
+            let offset = -(iteration as i32) * s;
+            let mut bounds: Option<(i32, i32, i32, i32)> = None;
+            for r in 0..image.rows as i32 {
+                for c in 0..image.cols as i32 {
+                    if image[(r, c)] != image.default {
+                        let (r, c) = (r + offset, c + offset);
+                        let (top, left, bottom, right) = bounds.unwrap_or((r, c, r + 1, c + 1));
+                        bounds =
+                            Some((top.min(r), left.min(c), bottom.max(r + 1), right.max(c + 1)));
+                    }
+                }
+            }
+            Stats {
+                iteration,
+                lit: image.lit_count(),
+                background: image.default,
+                bounds,
+                changed,
+            }
+        };
+
+        let mut image = image.clone();
+        emit(stats(0, &image, LitCount::Finite(0)));
+        for iteration in 1..=times {
+            let next = self.enhance_iteration(image.clone());
+            let changed = if next.default != image.default {
+                LitCount::Infinite
+            } else {
+                let mut changed = 0;
+                for r in 0..next.rows as i32 {
+                    for c in 0..next.cols as i32 {
+                        if next[(r, c)] != image[(r - s, c - s)] {
+                            changed += 1;
+                        }
+                    }
+                }
+                LitCount::Finite(changed)
+            };
+            emit(stats(iteration, &next, changed));
+            image = next;
+        }
+
+        image
+    }
+}
+
+// A state that comes back: from step `start` on the sequence repeats every `period` steps,
+// moved by `displacement` (rows, columns) each time
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cycle {
+    start: usize,
+    period: usize,
+    displacement: (i32, i32),
+}
+
+impl ImageEnhancer {
+    // Compares trimmed states, so the same pattern at another position (or with a grown border) matches
+    fn find_cycle(&self, image: &Image, max_steps: usize) -> Option<Cycle> {
+        let mut states: Vec<TrimmedImage> = Vec::new();
+        let mut seen: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut state = TrimmedImage::new(image, 0);
+
+        for step in 0..=max_steps {
+            let mut hasher = DefaultHasher::new();
+            state.image.hash(&mut hasher);
+            let steps = seen.entry(hasher.finish()).or_default();
+
+            // Equal hashes are compared in full, collisions are just skipped
+            if let Some(start) = steps.iter().find(|s| states[**s].image == state.image) {
+                let earlier = &states[*start];
+                // An empty plane has no position
+                let displacement = if state.image.data.is_empty() {
+                    (0, 0)
+                } else {
+                    (state.top - earlier.top, state.left - earlier.left)
+                };
+                return Some(Cycle {
+                    start: *start,
+                    period: step - start,
+                    displacement,
+                });
+            }
+
+            steps.push(step);
+            states.push(state.clone());
+            state = state.enhanced(self);
+        }
+
+        None
+    }
+}
+
+// "ToString" functionality for Cycle
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.period, self.displacement) {
+            (1, (0, 0)) => write!(f, "fixed point")?,
+            (period, (0, 0)) => write!(f, "oscillator with period {}", period)?,
+            (period, (r, c)) => write!(
+                f,
+                "spaceship with period {} moving by ({}, {})",
+                period, r, c
+            )?,
+        }
+        write!(f, " from step {}", self.start)
+    }
+}
+
+// Bit of the middle pixel in a window index (read row by row, top-left pixel is the high bit)
+const CENTRE: usize = 1 << 4;
+
+// Life-like rule: birth and survival depend only on how many of the 8 neighbours are lit
+#[derive(Clone, Debug, PartialEq)]
+struct LifeRule {
+    birth: [bool; 9],
+    survival: [bool; 9],
+}
+
+impl ImageEnhancer {
+    // Any rule that only looks at the centre pixel and the number of lit neighbours
+    fn outer_totalistic(rule: impl Fn(bool, usize) -> bool) -> ImageEnhancer {
+        let algorithm = (0..512usize)
+            .map(|i| rule(i & CENTRE != 0, (i & !CENTRE).count_ones() as usize))
+            .collect();
+        ImageEnhancer::with_kernel(Kernel::default(), algorithm).unwrap()
+    }
+
+    // Some(rule) when the table is exactly what that rulestring compiles to
+    fn life_rule(&self) -> Option<LifeRule> {
+        if self.kernel != Kernel::default() {
+            return None;
+        }
+
+        // Window indices with the first n neighbours lit
+        let neighbours = |n: usize| {
+            (0..9)
+                .filter(|bit| 1 << bit != CENTRE)
+                .take(n)
+                .fold(0, |index, bit| index | 1 << bit)
+        };
+
+        let mut rule = LifeRule {
+            birth: [false; 9],
+            survival: [false; 9],
+        };
+        for n in 0..9 {
+            rule.birth[n] = *self.algorithm.get(neighbours(n))?;
+            rule.survival[n] = *self.algorithm.get(neighbours(n) | CENTRE)?;
+        }
+
+        if ImageEnhancer::from(&rule).algorithm == self.algorithm {
+            Some(rule)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<&LifeRule> for ImageEnhancer {
+    fn from(rule: &LifeRule) -> Self {
+        ImageEnhancer::outer_totalistic(|centre, n| {
+            if centre {
+                rule.survival[n]
+            } else {
+                rule.birth[n]
+            }
+        })
+    }
+}
+
+// "Parse" functionality for LifeRule: "B3/S23" (either order), or the older survival-first "23/3"
+impl FromStr for LifeRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let counts = |digits: &str| {
+            let mut counts = [false; 9];
+            for c in digits.chars() {
+                let n = c.to_digit(10).filter(|n| *n <= 8).ok_or(())?;
+                counts[n as usize] = true;
+            }
+            Ok(counts)
+        };
+
+        let (a, b) = s.trim().split_once('/').ok_or(())?;
+        let prefixed = |part: &str| part.chars().next().map(|c| c.to_ascii_uppercase());
+
+        let (birth, survival) = match (prefixed(a), prefixed(b)) {
+            (Some('B'), Some('S')) => (&a[1..], &b[1..]),
+            (Some('S'), Some('B')) => (&b[1..], &a[1..]),
+            (Some('B' | 'S'), _) | (_, Some('B' | 'S')) => return Err(()),
+            _ => (b, a),
+        };
+
+        Ok(LifeRule {
+            birth: counts(birth)?,
+            survival: counts(survival)?,
+        })
+    }
+}
+
+// "ToString" functionality for LifeRule
+impl fmt::Display for LifeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "B")?;
+        for n in (0..9).filter(|n| self.birth[*n]) {
+            write!(f, "{}", n)?;
+        }
+        write!(f, "/S")?;
+        for n in (0..9).filter(|n| self.survival[*n]) {
+            write!(f, "{}", n)?;
+        }
+        Ok(())
+    }
+}
+
+// Hash-consed quadtree node: a single pixel, or four equal-sized children (nw, ne, sw, se)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Leaf(bool),
+    Branch(u32, [usize; 4]),
+}
+
+// Hashlife: identical blocks share one node, and the future centre of every node is memoized,
+// so repetitive patterns can be advanced by huge numbers of steps at once.
+// Everything outside `root` is `background`
+struct HashLife {
+    algorithm: Vec<bool>,
+    nodes: Vec<Node>,
+    population: Vec<usize>,
+    ids: HashMap<Node, usize>,
+    // (node, j) -> centre of the node after 2^j steps
+    results: HashMap<(usize, u32), usize>,
+    root: usize,
+    top: i64,
+    left: i64,
+    background: bool,
+    // Area the equivalent Image covers: the starting image grown by one pixel per step
+    rows: usize,
+    cols: usize,
+    generation: u64,
+}
+
+impl HashLife {
+    fn new(enhancer: &ImageEnhancer, image: &Image) -> HashLife {
+        assert_eq!(enhancer.kernel, Kernel::default(), "hashlife is 3x3 only");
+        let mut result = HashLife {
+            algorithm: enhancer.algorithm.clone(),
+            nodes: Vec::new(),
+            population: Vec::new(),
+            ids: HashMap::new(),
+            results: HashMap::new(),
+            root: 0,
+            top: 0,
+            left: 0,
+            background: image.default,
+            rows: image.rows,
+            cols: image.cols,
+            generation: 0,
+        };
+        // Leaves get ids 0 and 1, so a pixel's id is the pixel itself
+        result.node(Node::Leaf(false));
+        result.node(Node::Leaf(true));
+
+        let mut level = 1;
+        while 1 << level < image.rows.max(image.cols) {
+            level += 1;
+        }
+        result.root = result.build(image, 0, 0, level);
+        result
+    }
+
+    fn build(&mut self, image: &Image, top: i32, left: i32, level: u32) -> usize {
+        if level == 0 {
+            return image[(top, left)] as usize;
+        }
+        let half = 1 << (level - 1);
+        let children = [
+            self.build(image, top, left, level - 1),
+            self.build(image, top, left + half, level - 1),
+            self.build(image, top + half, left, level - 1),
+            self.build(image, top + half, left + half, level - 1),
+        ];
+        self.join(children)
+    }
+
+    fn node(&mut self, node: Node) -> usize {
+        if let Some(id) = self.ids.get(&node) {
+            return *id;
+        }
+        let population = match node {
+            Node::Leaf(lit) => lit as usize,
+            Node::Branch(_, children) => children.iter().map(|c| self.population[*c]).sum(),
+        };
+        self.nodes.push(node);
+        self.population.push(population);
+        self.ids.insert(node, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    fn join(&mut self, children: [usize; 4]) -> usize {
+        self.node(Node::Branch(self.level(children[0]) + 1, children))
+    }
+
+    fn level(&self, id: usize) -> u32 {
+        match self.nodes[id] {
+            Node::Leaf(_) => 0,
+            Node::Branch(level, _) => level,
+        }
+    }
+
+    fn children(&self, id: usize) -> [usize; 4] {
+        match self.nodes[id] {
+            Node::Leaf(_) => panic!("leaf has no children"),
+            Node::Branch(_, children) => children,
+        }
+    }
+
+    fn uniform(&mut self, level: u32, lit: bool) -> usize {
+        if level == 0 {
+            return lit as usize;
+        }
+        let child = self.uniform(level - 1, lit);
+        self.join([child; 4])
+    }
+
+    // Middle half of a node, one level down
+    fn centre(&mut self, id: usize) -> usize {
+        let [nw, ne, sw, se] = self.children(id);
+        self.join([
+            self.children(nw)[3],
+            self.children(ne)[2],
+            self.children(sw)[1],
+            self.children(se)[0],
+        ])
+    }
+
+    // Centre of a level k node after 2^j steps, for j <= k - 2
+    fn result(&mut self, id: usize, j: u32) -> usize {
+        if let Some(result) = self.results.get(&(id, j)) {
+            return *result;
+        }
+
+        let level = self.level(id);
+        let result = if level == 2 {
+            // 4x4 pixels, the 2x2 centre is one step away
+            let mut pixels = [[false; 4]; 4];
+            for (q, quadrant) in self.children(id).into_iter().enumerate() {
+                for (p, pixel) in self.children(quadrant).into_iter().enumerate() {
+                    pixels[q / 2 * 2 + p / 2][q % 2 * 2 + p % 2] = pixel == 1;
+                }
+            }
+            let out = |r: usize, c: usize| {
+                let mut index = 0;
+                for row in &pixels[r - 1..=r + 1] {
+                    for pixel in &row[c - 1..=c + 1] {
+                        index = index << 1 | *pixel as usize;
+                    }
+                }
+                self.algorithm[index] as usize
+            };
+            let centre = [out(1, 1), out(1, 2), out(2, 1), out(2, 2)];
+            self.join(centre)
+        } else {
+            let [nw, ne, sw, se] = self.children(id);
+            let [nw, ne, sw, se] = [nw, ne, sw, se].map(|n| self.children(n));
+
+            // Nine overlapping squares one level down, three by three
+            let squares = [
+                self.join([nw[0], nw[1], nw[2], nw[3]]),
+                self.join([nw[1], ne[0], nw[3], ne[2]]),
+                self.join([ne[0], ne[1], ne[2], ne[3]]),
+                self.join([nw[2], nw[3], sw[0], sw[1]]),
+                self.join([nw[3], ne[2], sw[1], se[0]]),
+                self.join([ne[2], ne[3], se[0], se[1]]),
+                self.join([sw[0], sw[1], sw[2], sw[3]]),
+                self.join([sw[1], se[0], sw[3], se[2]]),
+                self.join([se[0], se[1], se[2], se[3]]),
+            ];
+
+            // At full speed both halves advance 2^(k-3) steps, otherwise only the second one moves
+            let mut moved = [0; 9];
+            for (i, square) in squares.into_iter().enumerate() {
+                moved[i] = if j + 2 == level {
+                    self.result(square, j - 1)
+                } else {
+                    self.centre(square)
+                };
+            }
+
+            let m = moved;
+            let quadrants = [
+                self.join([m[0], m[1], m[3], m[4]]),
+                self.join([m[1], m[2], m[4], m[5]]),
+                self.join([m[3], m[4], m[6], m[7]]),
+                self.join([m[4], m[5], m[7], m[8]]),
+            ];
+            let quadrants = quadrants.map(|q| self.result(q, j.min(level - 3)));
+            self.join(quadrants)
+        };
+
+        self.results.insert((id, j), result);
+        result
+    }
+
+    // Same node one level up, centred in background
+    fn expand(&mut self) {
+        let level = self.level(self.root);
+        let [nw, ne, sw, se] = self.children(self.root);
+        let e = self.uniform(level - 1, self.background);
+        let children = [[e, e, e, nw], [e, e, ne, e], [e, sw, e, e], [se, e, e, e]]
+            .map(|children| self.join(children));
+        self.root = self.join(children);
+        self.top -= 1 << (level - 1);
+        self.left -= 1 << (level - 1);
+    }
+
+    // Drop the outer ring of the root while it is only background
+    fn shrink(&mut self) {
+        loop {
+            let level = self.level(self.root);
+            if level < 2 {
+                return;
+            }
+            let e = self.uniform(level - 2, self.background);
+            let [nw, ne, sw, se] = self.children(self.root).map(|q| self.children(q));
+            let ring = [
+                nw[0], nw[1], nw[2], ne[0], ne[1], ne[3], sw[0], sw[2], sw[3], se[1], se[2], se[3],
+            ];
+            if ring.iter().any(|n| *n != e) {
+                return;
+            }
+            self.root = self.centre(self.root);
+            self.top += 1 << (level - 2);
+            self.left += 1 << (level - 2);
+        }
+    }
+
+    // One power-of-two jump: pad so the pattern cannot outgrow the result, then take the memoized centre
+    fn jump(&mut self, j: u32) {
+        while self.level(self.root) < j + 1 {
+            self.expand();
+        }
+        self.expand();
+        self.expand();
+        let level = self.level(self.root);
+        self.root = self.result(self.root, j);
+        self.top += 1 << (level - 2);
+        self.left += 1 << (level - 2);
+
+        // next_default applied 2^j times: every bool function repeats with period 2 after the first step
+        for _ in 0..if j == 0 { 1 } else { 2 } {
+            self.background = self.algorithm[if self.background { 511 } else { 0 }];
+        }
+        self.shrink();
+    }
+
+    fn advance(&mut self, steps: u64) {
+        for j in 0..64 {
+            if steps >> j & 1 == 1 {
+                self.jump(j);
+            }
+        }
+        self.generation += steps;
+    }
+
+    fn pixel(&self, r: i64, c: i64) -> bool {
+        let size = 1i64 << self.level(self.root);
+        let (mut r, mut c) = (r - self.top, c - self.left);
+        if r < 0 || c < 0 || r >= size || c >= size {
+            return self.background;
+        }
+
+        let mut id = self.root;
+        let mut half = size / 2;
+        while let Node::Branch(_, children) = self.nodes[id] {
+            id = children[(r >= half) as usize * 2 + (c >= half) as usize];
+            r %= half.max(1);
+            c %= half.max(1);
+            half /= 2;
+        }
+        id == 1
+    }
+
+    fn lit_count(&self) -> LitCount {
+        if self.background {
+            return LitCount::Infinite;
+        }
+        LitCount::Finite(self.population[self.root])
+    }
+
+    // Same area and contents as the dense engine after `generation` steps
+    fn to_dense(&self) -> Image {
+        let g = self.generation as usize;
+        let mut result = Image::new(self.rows + g * 2, self.cols + g * 2, self.background);
+        for r in 0..result.rows {
+            for c in 0..result.cols {
+                result.data[r * result.cols + c] =
+                    self.pixel(r as i64 - g as i64, c as i64 - g as i64);
+            }
+        }
+        result
+    }
+}
+
+// Small deterministic generator for fixtures, no external crates
+struct Lcg(u64);
+
+impl Lcg {
+    fn next_bool(&mut self) -> bool {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 63 == 1
+    }
+
+    fn image(&mut self, rows: usize, cols: usize) -> Image {
+        let mut image = Image::new(rows, cols, false);
+        for pixel in image.data.iter_mut() {
+            *pixel = self.next_bool();
+        }
+        image
+    }
+
+    // `first`/`last` pin the background behaviour: steady, blinking or always lit
+    fn enhancer(&mut self, first: bool, last: bool) -> ImageEnhancer {
+        self.kernel_enhancer(Kernel::default(), first, last)
+    }
+
+    fn kernel_enhancer(&mut self, kernel: Kernel, first: bool, last: bool) -> ImageEnhancer {
+        let len = kernel.table_len().unwrap();
+        let mut algorithm: Vec<bool> = (0..len).map(|_| self.next_bool()).collect();
+        algorithm[0] = first;
+        algorithm[kernel.index(|_, _| true)] = last;
+        ImageEnhancer::with_kernel(kernel, algorithm).unwrap()
+    }
+}
+
+fn time<T>(label: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    println!("{:<24} {:>10.3?}", label, start.elapsed());
+    result
+}
+
+// Compare the backends on a random 100x100 image; build with -O for meaningful numbers
+fn bench() {
+    let mut rng = Lcg(20);
+    let mut enhancer = rng.enhancer(false, false);
+    let image = rng.image(100, 100);
+    let packed = PackedImage::from(&image);
+    let threads = thread::available_parallelism()
+        .map_or(4, |n| n.get())
+        .max(2);
+
+    for times in [50, 500] {
+        enhancer.threads = 1;
+        let dense = time(&format!("dense x{}", times), || {
+            enhancer.enhance(&image, times)
+        });
+        let packed_result = time(&format!("packed x{}", times), || {
+            enhancer.enhance(&packed, times)
+        });
+        assert_eq!(packed_result.to_dense(), dense);
+
+        enhancer.threads = threads;
+        let label = format!("dense x{}, {} threads", times, threads);
+        assert_eq!(time(&label, || enhancer.enhance(&image, times)), dense);
+        let label = format!("packed x{}, {} threads", times, threads);
+        assert_eq!(
+            time(&label, || enhancer.enhance(&packed, times)),
+            packed_result
+        );
+    }
+
+    let table = time("composite table", || CompositeTable::new(&enhancer));
+    enhancer.threads = 1;
+    for times in [50, 500] {
+        let dense = time(&format!("dense x{}", times), || {
+            enhancer.enhance(&image, times)
+        });
+        let composite = time(&format!("composite x{}", times), || {
+            enhancer.enhance_composite(&table, &image, times)
+        });
+        assert_eq!(composite, dense);
+    }
+
+    // Hashlife only pays off on repetitive patterns, so a Life soup rather than a random table
+    let life = ImageEnhancer::from(&"B3/S23".parse::<LifeRule>().unwrap());
+    let soup = rng.image(32, 32);
+    let dense = time("life dense x200", || life.enhance(&soup, 200));
+    let trimmed = time("life trimmed x200", || {
+        life.enhance(&TrimmedImage::new(&soup, 0), 200)
+    });
+    assert_eq!(trimmed.crop(-200, -200, dense.rows, dense.cols), dense);
+    println!(
+        "trimmed to {}x{} at ({}, {}) instead of {}x{}",
+        trimmed.image.rows, trimmed.image.cols, trimmed.top, trimmed.left, dense.rows, dense.cols
+    );
+    let mut hashlife = HashLife::new(&life, &soup);
+    time("life hashlife x200", || hashlife.advance(200));
+    assert_eq!(hashlife.to_dense(), dense);
+    let mut hashlife = HashLife::new(&life, &soup);
+    time("life hashlife x1000000", || hashlife.advance(1_000_000));
+    println!("{} lit", hashlife.lit_count());
+}
+
+fn main() {
+    if env::args().nth(1).as_deref() == Some("--bench") {
+        return bench();
+    }
+
+    let input = fs::read_to_string("input-day-20.txt").unwrap();
+    let mut iter = input.split("\n");
+
+    let a: Vec<&str> = iter.by_ref().take_while(|a| !a.is_empty()).collect();
+    let b: Vec<&str> = iter.collect();
+
+    let algorithm = a.join("");
+    let image_data = b.join("\n");
+
+    let mut image: Image = image_data.parse().unwrap();
+    let mut enhancer: ImageEnhancer = algorithm.parse().unwrap();
+
+    // Usage: day20 [--threads N] [--rule RULESTRING] [--image BITMAP] [--frames PATH.png|pbm|pgm [--margin N]] [--cycle STEPS] [--stats csv|json]
+    //              [--sparse] [--composite] [--hashlife]
+    let args: Vec<String> = env::args().collect();
+    let arg = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .map(|i| args[i + 1].as_str())
+    };
+    let flag = |name: &str| args.iter().any(|a| a == name);
+    if let Some(path) = arg("--image") {
+        image = Image::from_bitmap(&fs::read(path).unwrap()).unwrap();
+    }
+    if let Some(rule) = arg("--rule") {
+        let rule: LifeRule = rule.parse().unwrap();
+        enhancer.algorithm = ImageEnhancer::from(&rule).algorithm;
+    }
+    if let Some(threads) = arg("--threads") {
+        enhancer.threads = threads.parse().unwrap();
+    }
+
+    // Per-iteration stats for the 50 steps of part 2, nothing else
+    if let Some(format) = arg("--stats") {
+        let mut stats = Vec::new();
+        enhancer.enhance_with_stats(&image, 50, |s| stats.push(s));
+        if format == "json" {
+            println!("{}", stats_to_json(&stats));
+        } else {
+            println!("{}", Stats::CSV_HEADER);
+            for s in &stats {
+                println!("{}", s.to_csv());
+            }
+        }
+        return;
+    }
+
+    if let Some(rule) = enhancer.life_rule() {
+        println!("{}", rule);
+    }
+    println!("{}", enhancer.background());
+
+    if let Some(steps) = arg("--cycle") {
+        match enhancer.find_cycle(&image, steps.parse().unwrap()) {
+            Some(cycle) => println!("{}", cycle),
+            None => println!("no repeat within {} steps", steps),
+        }
+    }
+
+    // Iteration i goes to PATH-00i.ext
+    if let Some(pattern) = arg("--frames") {
+        let margin = arg("--margin").map_or(0, |m| m.parse().unwrap());
+        let (stem, ext) = pattern.rsplit_once('.').unwrap_or((pattern, "pbm"));
+        let mut frame = image.clone();
+        for i in 0..=50 {
+            let framed = frame.with_margin(margin);
+            let data = match ext {
+                "png" => framed.to_png(),
+                "pgm" => framed.to_pgm(),
+                _ => framed.to_pbm(),
+            };
+            fs::write(format!("{}-{:03}.{}", stem, i, ext), data).unwrap();
+            frame = enhancer.enhance(&frame, 1);
+        }
+    }
+
+    println!("{:?}", algorithm);
+    println!("{}", image);
+    println!("{}", enhancer.enhance(&image, 2).lit_count());
+    println!("{}", enhancer.enhance(&image, 50).lit_count());
+
+    // The other engines give the same counts, each can be run on its own for comparison
+    if flag("--sparse") {
+        let sparse = SparseImage::from(&image);
+        println!("{}", enhancer.enhance(&sparse, 2).lit_count());
+        println!("{}", enhancer.enhance(&sparse, 50).lit_count());
+    }
+
+    // Building the table takes longer than the puzzle itself
+    if flag("--composite") {
+        let table = CompositeTable::new(&enhancer);
+        println!(
+            "{}",
+            enhancer.enhance_composite(&table, &image, 2).lit_count()
+        );
+        println!(
+            "{}",
+            enhancer.enhance_composite(&table, &image, 50).lit_count()
+        );
+    }
+
+    if flag("--hashlife") {
+        let mut hashlife = HashLife::new(&enhancer, &image);
+        hashlife.advance(2);
+        println!("{}", hashlife.lit_count());
+        hashlife.advance(48);
+        println!("{}", hashlife.lit_count());
+    }
+
+    // println!("{}", enhancer.enhance(&image, 0));
+    // println!("{}", enhancer.enhance(&image, 1));
+    // println!("{}", enhancer.enhance(&image, 2));
+    // println!("{}", enhancer.enhance(&image, 3));
+    // println!("{}", enhancer.enhance(&image, 4));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deliberately naive: lit pixels in a set, every lookup bounds-checked against the tracked area
+    fn reference_enhance(
+        algorithm: &[bool],
+        kernel: &Kernel,
+        image: &Image,
+        times: usize,
+    ) -> Image {
+        let mut lit: HashSet<(i32, i32)> = HashSet::new();
+        for r in 0..image.rows as i32 {
+            for c in 0..image.cols as i32 {
+                if image[(r, c)] {
+                    lit.insert((r, c));
+                }
+            }
+        }
+
+        let (mut top, mut left) = (0, 0);
+        let (mut bottom, mut right) = (image.rows as i32, image.cols as i32);
+        let mut background = image.default;
+
+        for _ in 0..times {
+            let pixel = |r: i32, c: i32| {
+                if r < top || r >= bottom || c < left || c >= right {
+                    background
+                } else {
+                    lit.contains(&(r, c))
+                }
+            };
+
+            let s = kernel.radius();
+            let mut next = HashSet::new();
+            for r in top - s..bottom + s {
+                for c in left - s..right + s {
+                    let window: Vec<bool> = kernel
+                        .offsets
+                        .iter()
+                        .map(|(dr, dc)| pixel(r + dr, c + dc))
+                        .collect();
+                    let index = if kernel.counting {
+                        let centre = kernel.offsets.iter().position(|o| *o == (0, 0));
+                        let centre = centre.is_some_and(|i| window[i]) as usize;
+                        let others =
+                            kernel.offsets.len() - kernel.offsets.contains(&(0, 0)) as usize;
+                        let count = window.iter().filter(|p| **p).count() - centre;
+                        centre * (others + 1) + count
+                    } else {
+                        window.iter().fold(0, |index, p| index * 2 + *p as usize)
+                    };
+                    if algorithm[index] {
+                        next.insert((r, c));
+                    }
+                }
+            }
+
+            background = algorithm[kernel.index(|_, _| background)];
+            lit = next;
+            top -= s;
+            left -= s;
+            bottom += s;
+            right += s;
+        }
+
+        let mut result = Image::new((bottom - top) as usize, (right - left) as usize, background);
+        for (r, c) in lit {
+            result[(r - top, c - left)] = true;
+        }
+
+        result
+    }
+
+    // 24x6 8-bit greyscale PNG using every filter type, compressed with dynamic Huffman codes
+    const PNG_FIXTURE: [u8; 135] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0x06, 0x08, 0x00, 0x00, 0x00, 0x00, 0xfc,
+        0xc0, 0x01, 0xcf, 0x00, 0x00, 0x00, 0x4e, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x6d, 0xc1,
+        0xb1, 0x09, 0x80, 0x40, 0x0c, 0x40, 0xd1, 0xaf, 0x1e, 0x08, 0xd6, 0x6e, 0x60, 0x1f, 0x9c,
+        0xc5, 0x2d, 0x9c, 0xc8, 0xb5, 0x24, 0xcd, 0x55, 0x62, 0x17, 0x70, 0x0b, 0x13, 0xef, 0x40,
+        0x0b, 0xdf, 0x4b, 0x46, 0x21, 0xb8, 0x7d, 0x26, 0xe8, 0x31, 0xd1, 0x18, 0x85, 0xf0, 0x52,
+        0xa0, 0x95, 0x4a, 0x2b, 0x9c, 0xb8, 0x6e, 0x20, 0x2c, 0x79, 0xa5, 0xd8, 0xe4, 0xe2, 0x61,
+        0x6e, 0xfc, 0xb0, 0xd0, 0x9b, 0x25, 0xfe, 0x9c, 0x70, 0x03, 0x48, 0xd3, 0x1d, 0x95, 0x65,
+        0xe2, 0x4f, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+    const PNG_FIXTURE_IMAGE: &str = "
+########.....#......####
+########............####
+........########........
+........########........
+####............#####.##
+####............########
+";
+
+    const SIZES: [(usize, usize); 10] = [
+        (1, 1),
+        (1, 9),
+        (9, 1),
+        (2, 5),
+        (5, 2),
+        (3, 17),
+        (17, 3),
+        (6, 6),
+        (2, 63),
+        (3, 130),
+    ];
+
+    struct Fixture<'a> {
+        enhancer: &'a ImageEnhancer,
+        image: &'a Image,
+        times: usize,
+        expected: Image,
+        context: String,
+    }
+
+    // Rectangular fixtures (including 1xN and Nx1) stepped by `reference_enhance`, under a steady,
+    // a blinking and an always lit background
+    fn for_each_fixture(mut f: impl FnMut(&Fixture)) {
+        let mut rng = Lcg(2021);
+        for (first, last) in [(false, false), (true, false), (true, true)] {
+            let enhancer = rng.enhancer(first, last);
+            for (rows, cols) in SIZES {
+                let image = rng.image(rows, cols);
+                for times in 0..=4 {
+                    f(&Fixture {
+                        enhancer: &enhancer,
+                        image: &image,
+                        times,
+                        expected: reference_enhance(
+                            &enhancer.algorithm,
+                            &enhancer.kernel,
+                            &image,
+                            times,
+                        ),
+                        context: format!(
+                            "{}x{} image, {} iterations, background {}/{}",
+                            rows, cols, times, first, last
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dense_matches_reference() {
+        for_each_fixture(|f| {
+            let actual = f.enhancer.enhance(f.image, f.times);
+            assert_eq!(actual, f.expected, "{}", f.context);
+            assert_eq!(actual.lit_count(), f.expected.lit_count(), "{}", f.context);
+        });
+    }
+
+    #[test]
+    fn threads_match_reference() {
+        for_each_fixture(|f| {
+            let parallel = ImageEnhancer {
+                algorithm: f.enhancer.algorithm.clone(),
+                kernel: Kernel::default(),
+                threads: 3,
+            };
+            let dense = parallel.enhance(f.image, f.times);
+            assert_eq!(dense, f.expected, "{}", f.context);
+            let packed = parallel.enhance(&PackedImage::from(f.image), f.times);
+            assert_eq!(packed.to_dense(), f.expected, "packed, {}", f.context);
+        });
+    }
+
+    #[test]
+    fn packed_matches_reference() {
+        for_each_fixture(|f| {
+            let packed = f.enhancer.enhance(&PackedImage::from(f.image), f.times);
+            assert_eq!(packed.to_dense(), f.expected, "{}", f.context);
+            assert_eq!(packed.lit_count(), f.expected.lit_count(), "{}", f.context);
+        });
+    }
+
+    #[test]
+    fn sparse_matches_reference() {
+        for_each_fixture(|f| {
+            let sparse = f.enhancer.enhance(&SparseImage::from(f.image), f.times);
+            assert_eq!(sparse.to_dense(), f.expected, "{}", f.context);
+            assert_eq!(sparse.lit_count(), f.expected.lit_count(), "{}", f.context);
+        });
+    }
+
+    #[test]
+    fn trimmed_matches_reference() {
+        for_each_fixture(|f| {
+            let n = f.times as i32;
+            for margin in [0, 2] {
+                let trimmed = f
+                    .enhancer
+                    .enhance(&TrimmedImage::new(f.image, margin), f.times);
+                let area = trimmed.crop(-n, -n, f.expected.rows, f.expected.cols);
+                assert_eq!(area, f.expected, "margin {}, {}", margin, f.context);
+                assert_eq!(trimmed.lit_count(), f.expected.lit_count(), "{}", f.context);
+            }
+        });
+    }
+
+    #[test]
+    fn hashlife_matches_reference() {
+        for_each_fixture(|f| {
+            let mut hashlife = HashLife::new(f.enhancer, f.image);
+            hashlife.advance(f.times as u64);
+            assert_eq!(hashlife.to_dense(), f.expected, "{}", f.context);
+            assert_eq!(
+                hashlife.lit_count(),
+                f.expected.lit_count(),
+                "{}",
+                f.context
+            );
+        });
+    }
+
+    // Jumps of several powers of two at once, and split into uneven pieces
+    #[test]
+    fn hashlife_long_jumps() {
+        let mut rng = Lcg(2021);
+        for (first, last) in [(false, false), (true, false), (true, true)] {
+            let enhancer = rng.enhancer(first, last);
+            let image = rng.image(7, 12);
+            let mut hashlife = HashLife::new(&enhancer, &image);
+            for times in [1, 5, 32, 39, 77] {
+                let expected = enhancer.enhance(&image, times);
+                hashlife.advance(times as u64 - hashlife.generation);
+                assert_eq!(hashlife.to_dense(), expected, "{} iterations", times);
+                let mut fresh = HashLife::new(&enhancer, &image);
+                fresh.advance(times as u64);
+                assert_eq!(fresh.to_dense(), expected, "{} iterations", times);
+            }
+        }
+    }
+
+    // Other kernels through the engines that support them
+    #[test]
+    fn other_kernels_match_reference() {
+        let mut rng = Lcg(2021);
+        let mut reversed = Kernel::default();
+        reversed.offsets.reverse();
+        let kernels = [
+            Kernel::von_neumann(),
+            Kernel::hexagonal(),
+            reversed,
+            "..#..\n.###.\n##.##\n.###.\n..#..".parse().unwrap(),
+            Kernel::square(2).counting(),
+            Kernel::hexagonal().counting(),
+            "#.#\n...\n#.#".parse::<Kernel>().unwrap().counting(),
+        ];
+        for kernel in kernels {
+            for (first, last) in [(false, false), (true, false)] {
+                let enhancer = rng.kernel_enhancer(kernel.clone(), first, last);
+                for (rows, cols) in [(1, 1), (2, 5), (6, 6), (9, 1)] {
+                    let image = rng.image(rows, cols);
+                    for times in 0..=3 {
+                        let expected =
+                            reference_enhance(&enhancer.algorithm, &kernel, &image, times);
+                        let actual = enhancer.enhance(&image, times);
+                        assert_eq!(
+                            actual, expected,
+                            "{:?}, {}x{}, {} iterations",
+                            kernel, rows, cols, times
+                        );
+                        let sparse = enhancer.enhance(&SparseImage::from(&image), times);
+                        assert_eq!(sparse.to_dense(), expected, "sparse, {:?}", kernel);
+                        let n = times as i32 * kernel.radius();
+                        let trimmed = enhancer.enhance(&TrimmedImage::new(&image, 0), times);
+                        let area = trimmed.crop(-n, -n, expected.rows, expected.cols);
+                        assert_eq!(area, expected, "trimmed, {:?}", kernel);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_width_images() {
+        let kernel: Kernel = "#".parse().unwrap();
+        let mut enhancer = ImageEnhancer::with_kernel(kernel, vec![true, false]).unwrap();
+        for threads in [1, 3] {
+            enhancer.threads = threads;
+            let image = Image::new(3, 0, false);
+            assert_eq!(enhancer.enhance(&image, 1), Image::new(3, 0, true));
+            assert_eq!(enhancer.enhance(&image, 2), image);
+        }
+    }
+
+    #[test]
+    fn counting_table_matches_life() {
+        let image = Lcg(2021).image(10, 10);
+        let life = ImageEnhancer::from(&"B3/S23".parse::<LifeRule>().unwrap());
+        let counting = ImageEnhancer::counting(&Kernel::default(), &[3], &[2, 3]);
+        assert_eq!(counting.algorithm.len(), 18);
+        assert_eq!(counting.enhance(&image, 5), life.enhance(&image, 5));
+        assert_eq!(counting.life_rule(), None);
+    }
+
+    // Without the centre in the kernel the all-lit window is not the last table entry
+    #[test]
+    fn counting_kernel_without_centre() {
+        let kernel = "#\n.\n#".parse::<Kernel>().unwrap().counting();
+        let algorithm = [true, false, true, false, false, false].to_vec();
+        let enhancer = ImageEnhancer::with_kernel(kernel.clone(), algorithm).unwrap();
+        assert_eq!(enhancer.background(), Background::Lit);
+
+        let image = Image::new(1, 1, false);
+        let expected = reference_enhance(&enhancer.algorithm, &kernel, &image, 2);
+        assert_eq!(expected.lit_count(), LitCount::Infinite);
+        assert_eq!(enhancer.enhance(&image, 2), expected);
+        let sparse = enhancer.enhance(&SparseImage::from(&image), 2);
+        assert_eq!(sparse.to_dense(), expected);
+        let trimmed = enhancer.enhance(&TrimmedImage::new(&image, 0), 2);
+        assert_eq!(trimmed.crop(-2, -2, expected.rows, expected.cols), expected);
+    }
+
+    #[test]
+    fn kernel_table_lengths() {
+        assert_eq!(Kernel::square(2).counting().table_len(), Some(50));
+        assert_eq!(Kernel::von_neumann().table_len(), Some(32));
+        let wide: Kernel = ["#########"; 9].join("\n").parse().unwrap();
+        assert_eq!(wide.table_len(), None);
+        assert!(ImageEnhancer::with_kernel(wide.clone(), Vec::new()).is_err());
+        assert_eq!(wide.counting().table_len(), Some(162));
+        assert!("##\n##".parse::<Kernel>().is_err());
+        assert!(ImageEnhancer::with_kernel(Kernel::von_neumann(), vec![false; 512]).is_err());
+    }
+
+    // Stats follow the glider and the blinking background
+    #[test]
+    fn stats() {
+        let life = ImageEnhancer::from(&"B3/S23".parse::<LifeRule>().unwrap());
+        let glider: Image = ".#.\n..#\n###".parse().unwrap();
+        let mut stats = Vec::new();
+        let result = life.enhance_with_stats(&glider, 4, |s| stats.push(s));
+        assert_eq!(result, life.enhance(&glider, 4));
+        assert_eq!(stats.len(), 5);
+        assert_eq!(stats[0].bounds, Some((0, 0, 3, 3)));
+        assert_eq!(stats[4].bounds, Some((1, 1, 4, 4)));
+        assert!(stats.iter().all(|s| s.lit == LitCount::Finite(5)));
+        assert_eq!(stats[1].changed, LitCount::Finite(4));
+        assert_eq!(stats[1].to_csv(), "1,5,0,1,0,4,3,4");
+
+        let mut stats = Vec::new();
+        let blinking = Lcg(2021).enhancer(true, false);
+        blinking.enhance_with_stats(&Image::new(2, 2, false), 2, |s| stats.push(s));
+        assert_eq!(stats[1].changed, LitCount::Infinite);
+        assert_eq!(stats[1].bounds, None);
+        assert_eq!(
+            stats_to_json(&stats[..2]),
+            "[{\"iteration\":0,\"lit\":0,\"background\":false,\"bounds\":null,\"changed\":0},\
+             {\"iteration\":1,\"lit\":\"infinite\",\"background\":true,\"bounds\":null,\"changed\":\"infinite\"}]"
+        );
+    }
+
+    // Composite tables for random rules and images against two single steps, odd counts
+    // finishing with one
+    #[test]
+    fn composite_matches_single_steps() {
+        let mut rng = Lcg(2021);
+        for (first, last) in [(false, false), (true, false), (true, true)] {
+            let enhancer = rng.enhancer(first, last);
+            let table = CompositeTable::new(&enhancer);
+            for (rows, cols) in [(1, 1), (2, 5), (9, 1), (6, 6), (3, 17)] {
+                let image = rng.image(rows, cols);
+                for times in 0..=5 {
+                    let expected = enhancer.enhance(&image, times);
+                    let actual = enhancer.enhance_composite(&table, &image, times);
+                    assert_eq!(
+                        actual, expected,
+                        "{}x{}, {} iterations, background {}/{}",
+                        rows, cols, times, first, last
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bitmap_round_trips() {
+        let mut rng = Lcg(2021);
+        for (rows, cols) in SIZES {
+            let image = rng.image(rows, cols);
+            assert_eq!(
+                Image::from_bitmap(&image.to_pbm()),
+                Ok(image.clone()),
+                "PBM"
+            );
+            assert_eq!(
+                Image::from_bitmap(&image.to_pgm()),
+                Ok(image.clone()),
+                "PGM"
+            );
+            assert_eq!(
+                Image::from_bitmap(&image.to_png()),
+                Ok(image.clone()),
+                "PNG"
+            );
+        }
+
+        // More than one stored block
+        let image = rng.image(300, 2000);
+        assert_eq!(Image::from_bitmap(&image.to_png()), Ok(image));
+    }
+
+    #[test]
+    fn bitmap_margin() {
+        let mut lit = Image::new(1, 2, true);
+        lit[(0, 1)] = true;
+        assert_eq!(lit.with_margin(1).to_string(), "####\n#.##\n####\n");
+    }
+
+    #[test]
+    fn forged_bitmap_sizes() {
+        let huge = 1usize << 32;
+        for header in [
+            format!("P1 {} {} 0", huge, huge),
+            format!("P4\n{} {}\n\0", huge, huge),
+            format!("P5 {} {} 65535\n\0\0", huge / 2, huge),
+        ] {
+            assert_eq!(Image::from_bitmap(header.as_bytes()), Err(()), "{}", header);
+        }
+
+        let mut header = Vec::new();
+        header.extend(u32::MAX.to_be_bytes());
+        header.extend(u32::MAX.to_be_bytes());
+        header.extend([1, 0, 0, 0, 0]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&[0; 16]));
+        png_chunk(&mut png, b"IEND", &[]);
+        assert_eq!(Image::from_bitmap(&png), Err(()));
+    }
+
+    #[test]
+    fn plain_pnm() {
+        let plain = b"P1\n# comment\n3 2\n0 1 0\n101";
+        assert_eq!(Image::from_bitmap(plain), "#.#\n.#.".parse());
+        assert_eq!(Image::from_bitmap(b"P2 2 1 10 6 5"), "#.".parse());
+    }
+
+    // A zlib-compressed (dynamic Huffman, Paeth filtered) 8-bit PNG
+    #[test]
+    fn png_fixture() {
+        assert_eq!(Image::from_bitmap(&PNG_FIXTURE), PNG_FIXTURE_IMAGE.parse());
+        let mut corrupt = PNG_FIXTURE.to_vec();
+        corrupt[60] ^= 1;
+        assert_eq!(Image::from_bitmap(&corrupt), Err(()));
+    }
+
+    #[test]
+    fn parse_enhancer() {
+        let table: String = (0..512)
+            .map(|i| if i % 3 == 0 { '#' } else { '.' })
+            .collect();
+        assert!(table.parse::<ImageEnhancer>().is_ok());
+        assert!(table[1..].parse::<ImageEnhancer>().is_err());
+        assert!(format!("{}.", table).parse::<ImageEnhancer>().is_err());
+        assert!(table
+            .replacen('.', "x", 1)
+            .parse::<ImageEnhancer>()
+            .is_err());
+    }
+
+    #[test]
+    fn background() {
+        let table: String = (0..512)
+            .map(|i| if i % 3 == 0 { '#' } else { '.' })
+            .collect();
+        let enhancer: ImageEnhancer = table.parse().unwrap();
+        assert_eq!(enhancer.background(), Background::Blinking);
+        assert_eq!(
+            enhancer.enhance(&Image::new(2, 2, false), 1).lit_count(),
+            LitCount::Infinite
+        );
+        assert_eq!(
+            enhancer.enhance(&Image::new(2, 2, false), 2).lit_count(),
+            LitCount::Finite(0)
+        );
+
+        let mut rng = Lcg(2021);
+        assert_eq!(rng.enhancer(false, true).background(), Background::Dark);
+        assert_eq!(rng.enhancer(true, true).background(), Background::Lit);
+    }
+
+    #[test]
+    fn life_rules() {
+        for rulestring in ["B3/S23", "B36/S23", "B0/S8", "B/S", "B012345678/S012345678"] {
+            let rule: LifeRule = rulestring.parse().unwrap();
+            assert_eq!(rule.to_string(), rulestring);
+            assert_eq!(ImageEnhancer::from(&rule).life_rule(), Some(rule));
+        }
+        assert_eq!("23/3".parse(), "B3/S23".parse::<LifeRule>());
+        assert_eq!("s23/b3".parse(), "B3/S23".parse::<LifeRule>());
+        for bad in ["B9/S23", "B3", "B3/23", "X3/S23", "B3/S2a"] {
+            assert_eq!(bad.parse::<LifeRule>(), Err(()), "{}", bad);
+        }
+        assert_eq!(Lcg(2021).enhancer(false, false).life_rule(), None);
+
+        // Blinker under Conway's Life
+        let life = ImageEnhancer::from(&"B3/S23".parse::<LifeRule>().unwrap());
+        let blinker: Image = "###".parse().unwrap();
+        let expected: Image = "..#..\n..#..\n..#..".parse().unwrap();
+        assert_eq!(life.enhance(&blinker, 1), expected);
+    }
+
+    #[test]
+    fn cycles() {
+        let cycle = |start, period, displacement| {
+            Some(Cycle {
+                start,
+                period,
+                displacement,
+            })
+        };
+        let life = ImageEnhancer::from(&"B3/S23".parse::<LifeRule>().unwrap());
+        let patterns = [
+            ("##\n##", cycle(0, 1, (0, 0))),
+            ("#..\n..#", cycle(1, 1, (0, 0))),
+            ("###", cycle(0, 2, (0, 0))),
+            (".#.\n..#\n###", cycle(0, 4, (1, 1))),
+            ("##\n#.", cycle(1, 1, (0, 0))),
+            ("##.\n.##\n.#.", None),
+        ];
+        for (pattern, expected) in patterns {
+            let image: Image = pattern.parse().unwrap();
+            assert_eq!(life.find_cycle(&image, 100), expected, "{}", pattern);
+        }
+
+        // Empty plane under a blinking background
+        let blinking = Lcg(2021).enhancer(true, false);
+        let empty = Image::new(0, 0, false);
+        assert_eq!(blinking.find_cycle(&empty, 10), cycle(0, 2, (0, 0)));
+    }
 }
@@ -0,0 +1,52 @@
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+
+include!("../../day16-rust.rs");
+
+// Arbitrary text through the whole pipeline: nothing may panic, and whatever decodes must survive
+// a re-encode and keep its value through simplification
+fuzz_target!(|data: &[u8]| {
+    // Raw bytes decode exactly like their hex spelling
+    let hex: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+    assert_eq!(
+        BitStream::from_bytes(data).read_packet(),
+        BitStream::from_hex_str(&hex).unwrap().read_packet()
+    );
+
+    let text = String::from_utf8_lossy(data);
+
+    let mut stream = match BitStream::from_hex_str(&text) {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    let single_pass = BitStream::from_hex_str(&text).unwrap().eval_single_pass();
+    let packet = match stream.read_packet() {
+        Ok(packet) => packet,
+        Err(e) => {
+            assert_eq!(single_pass, Err(e));
+            return;
+        }
+    };
+    assert_eq!(
+        single_pass,
+        Ok(Evaluation {
+            value: packet.checked_eval(),
+            version_sum: packet.version_sum(),
+        })
+    );
+
+    let _ = validate_padding(&stream.remaining());
+    let issues = packet.validate();
+    let value = packet.checked_eval();
+
+    let reencoded = BitStream::from_vec(packet.to_bits()).read_packet();
+    assert_eq!(reencoded.as_ref(), Ok(&packet));
+    assert_eq!(packet.bit_len(), packet.to_bits().len());
+
+    let simplified = packet.simplify().with_shortest_lengths();
+    assert!(simplified.validate().len() <= issues.len() || value.is_none());
+    assert_eq!(simplified.checked_eval(), value);
+    let _ = simplified.to_string();
+});
@@ -0,0 +1,68 @@
+#![no_main]
+#![allow(dead_code)]
+
+use arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+include!("../../day16-rust.rs");
+
+fn arbitrary_packet(u: &mut Unstructured, depth: usize) -> Result<Packet> {
+    let version = u.int_in_range(0..=7)?;
+
+    if depth == 0 || u.ratio(1, 3)? {
+        return Ok(Packet::Literal {
+            version,
+            type_id: 4,
+            value: u.arbitrary()?,
+        });
+    }
+
+    let type_id = *u.choose(&[0, 1, 2, 3, 5, 6, 7])?;
+    let length_type = *u.choose(&[LengthType::TotalBits, LengthType::SubPacketCount])?;
+    let count = if type_id > 4 {
+        2
+    } else {
+        u.int_in_range(1..=4)?
+    };
+    let packets = (0..count)
+        .map(|_| arbitrary_packet(u, depth - 1))
+        .collect::<Result<_>>()?;
+
+    Ok(Packet::Operator {
+        version,
+        type_id,
+        length_type,
+        packets,
+    })
+}
+
+// Valid packets built from fuzzer bytes must decode back from their own hex unchanged
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let packet = match arbitrary_packet(&mut u, 6) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    assert_eq!(packet.validate(), vec![]);
+
+    let hex = packet.to_hex();
+    let mut stream = BitStream::from_hex_str(&hex).unwrap();
+    assert_eq!(stream.read_packet(), Ok(packet.clone()));
+    assert_eq!(validate_padding(&stream.remaining()), vec![]);
+    assert_eq!(
+        BitStream::from_hex_str(&hex).unwrap().eval_single_pass(),
+        Ok(Evaluation {
+            value: packet.checked_eval(),
+            version_sum: packet.version_sum(),
+        })
+    );
+
+    let minimal = packet.with_shortest_lengths();
+    assert!(minimal.bit_len() <= packet.bit_len());
+    assert_eq!(minimal.checked_eval(), packet.checked_eval());
+
+    let value = packet.checked_eval();
+    if value.is_some() {
+        assert_eq!(packet.simplify().checked_eval(), value);
+    }
+});
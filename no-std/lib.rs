@@ -0,0 +1,7 @@
+// The day 16 decoder as a `#![no_std]` library, for targets without an operating system
+#![no_std]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+include!("../day16-rust.rs");